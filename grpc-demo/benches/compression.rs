@@ -0,0 +1,168 @@
+//! Size and latency of `Say` calls with each message compression, over a
+//! local connection. Run with `cargo bench --bench compression`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use http_body_util::BodyExt;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use tokio::net::TcpListener;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Channel, Server};
+use tower::util::{MapRequestLayer, MapResponseLayer};
+
+use grpc_demo::echo::v1::echo_service_client::EchoServiceClient;
+use grpc_demo::echo::v1::echo_service_server::EchoServiceServer;
+use grpc_demo::echo::v1::SayRequest;
+use grpc_demo::service::MyEchoService;
+use grpc_demo::validate::{TextRules, Validator};
+
+// bytes of the messages sent and received by the server, on the wire.
+static REQUEST_BYTES: AtomicU64 = AtomicU64::new(0);
+static RESPONSE_BYTES: AtomicU64 = AtomicU64::new(0);
+
+// bytes sent by each configuration, so large messages get fewer calls.
+const BYTES_PER_RUN: usize = 16 << 20;
+
+/// body counting its data bytes.
+fn counted(body: tonic::body::Body, bytes: &'static AtomicU64) -> tonic::body::Body {
+    tonic::body::Body::new(body.map_frame(move |frame| {
+        if let Some(data) = frame.data_ref() {
+            bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        frame
+    }))
+}
+
+/// spawn an echo server accepting every compression, and return its address.
+async fn spawn_server() -> std::net::SocketAddr {
+    let validator = Validator::new().message(TextRules {
+        min_chars: 1,
+        max_chars: 4 << 20,
+    });
+    let echo = EchoServiceServer::new(MyEchoService::new(validator))
+        .accept_compressed(CompressionEncoding::Gzip)
+        .send_compressed(CompressionEncoding::Gzip)
+        .accept_compressed(CompressionEncoding::Zstd)
+        .send_compressed(CompressionEncoding::Zstd);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // without it, small responses wait for delayed acknowledgements.
+    let incoming = TcpIncoming::from(listener).with_nodelay(Some(true));
+    tokio::spawn(
+        Server::builder()
+            .layer(MapRequestLayer::new(
+                |request: http::Request<tonic::body::Body>| {
+                    request.map(|body| counted(body, &REQUEST_BYTES))
+                },
+            ))
+            .layer(MapResponseLayer::new(
+                |response: http::Response<tonic::body::Body>| {
+                    response.map(|body| counted(body, &RESPONSE_BYTES))
+                },
+            ))
+            .add_service(echo)
+            .serve_with_incoming(incoming),
+    );
+    addr
+}
+
+/// English-like text, compressing well.
+fn text(size: usize) -> String {
+    let words = ["echo", "tonic", "message", "the", "a", "compressed", "gRPC"];
+    let mut rng = rand::thread_rng();
+    let mut text = String::with_capacity(size + 16);
+    while text.len() < size {
+        text.push_str(words[rng.gen_range(0..words.len())]);
+        text.push(' ');
+    }
+    text.truncate(size);
+    text
+}
+
+/// random letters and digits, compressing poorly.
+fn random(size: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(size)
+        .map(char::from)
+        .collect()
+}
+
+/// Outcome of the calls of a configuration.
+struct Run {
+    request_bytes: u64,
+    response_bytes: u64,
+    mean: Duration,
+    p50: Duration,
+}
+
+async fn run(channel: Channel, encoding: Option<CompressionEncoding>, message: &str) -> Run {
+    let mut client = EchoServiceClient::new(channel).max_decoding_message_size(8 << 20);
+    if let Some(encoding) = encoding {
+        client = client.send_compressed(encoding).accept_compressed(encoding);
+    }
+    let calls = (BYTES_PER_RUN / message.len()).clamp(10, 2000);
+    let request = SayRequest {
+        message: message.into(),
+    };
+    // warm up the connection and the compressors.
+    client.say(request.clone()).await.unwrap();
+
+    REQUEST_BYTES.store(0, Ordering::Relaxed);
+    RESPONSE_BYTES.store(0, Ordering::Relaxed);
+    let mut latencies = Vec::with_capacity(calls);
+    for _ in 0..calls {
+        let started_at = Instant::now();
+        client.say(request.clone()).await.unwrap();
+        latencies.push(started_at.elapsed());
+    }
+    latencies.sort_unstable();
+    Run {
+        request_bytes: REQUEST_BYTES.load(Ordering::Relaxed) / calls as u64,
+        response_bytes: RESPONSE_BYTES.load(Ordering::Relaxed) / calls as u64,
+        mean: latencies.iter().sum::<Duration>() / calls as u32,
+        p50: latencies[calls / 2],
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let addr = spawn_server().await;
+    let channel = Channel::from_shared(format!("http://{}", addr))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+
+    println!(
+        "{:<8} {:>9} {:<6} {:>12} {:>12} {:>7} {:>11} {:>11}",
+        "payload", "size", "codec", "request", "response", "ratio", "mean", "p50"
+    );
+    for size in [1 << 10, 64 << 10, 1 << 20].iter() {
+        for (payload, message) in [("text", text(*size)), ("random", random(*size))].iter() {
+            for (name, encoding) in [
+                ("none", None),
+                ("gzip", Some(CompressionEncoding::Gzip)),
+                ("zstd", Some(CompressionEncoding::Zstd)),
+            ]
+            .iter()
+            {
+                let run = run(channel.clone(), *encoding, message).await;
+                println!(
+                    "{:<8} {:>9} {:<6} {:>12} {:>12} {:>6.1}% {:>11.2?} {:>11.2?}",
+                    payload,
+                    size,
+                    name,
+                    run.request_bytes,
+                    run.response_bytes,
+                    run.request_bytes as f64 * 100.0 / *size as f64,
+                    run.mean,
+                    run.p50
+                );
+            }
+        }
+    }
+}
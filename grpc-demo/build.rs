@@ -1,4 +1,16 @@
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/echo.proto")?;
+    // use the vendored protoc, unless one is explicitly given.
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    tonic_prost_build::configure()
+        .file_descriptor_set_path(out_dir.join("echo_descriptor.bin"))
+        .compile_protos(
+            &["proto/echo/v1/echo.proto", "proto/echo.proto"],
+            &["proto"],
+        )?;
     Ok(())
 }
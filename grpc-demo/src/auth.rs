@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// Check bearer tokens, and tell who they belong to.
+pub trait TokenVerifier: Send + Sync + 'static {
+    /// return the subject of a valid token, or None if it is refused.
+    fn verify(&self, token: &str) -> Option<String>;
+}
+
+/// Subject of the token a request was authenticated with. Available in the
+/// request extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subject(pub String);
+
+/// Accept a fixed list of tokens.
+#[derive(Debug, Default, Clone)]
+pub struct StaticTokens {
+    // subject of each token.
+    tokens: HashMap<String, String>,
+}
+
+impl StaticTokens {
+    pub fn new() -> Self {
+        StaticTokens {
+            ..Default::default()
+        }
+    }
+
+    /// accept a token, belonging to a subject.
+    pub fn add(mut self, token: String, subject: String) -> Self {
+        self.tokens.insert(token, subject);
+        self
+    }
+}
+
+impl TokenVerifier for StaticTokens {
+    fn verify(&self, token: &str) -> Option<String> {
+        self.tokens.get(token).cloned()
+    }
+}
+
+/// Accept tokens signed with a shared key, formatted as
+/// `<subject>.<expiration unix time>.<hex HMAC-SHA256 of the first two parts>`.
+#[derive(Clone)]
+pub struct HmacTokens {
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for HmacTokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // never leak the key in logs.
+        f.debug_struct("HmacTokens").finish()
+    }
+}
+
+impl HmacTokens {
+    pub fn new(key: Vec<u8>) -> Self {
+        HmacTokens { key }
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// issue a token for a subject, valid for the given duration.
+    pub fn sign(&self, subject: &str, valid_for: Duration) -> String {
+        let expires_at = SystemTime::now() + valid_for;
+        let expires_at = expires_at.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let payload = format!("{}.{}", subject, expires_at);
+        let signature = hex::encode(self.mac(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+}
+
+impl TokenVerifier for HmacTokens {
+    fn verify(&self, token: &str) -> Option<String> {
+        // the subject may contain dots, but neither the time nor the signature.
+        let mut parts = token.rsplitn(3, '.');
+        let signature = hex::decode(parts.next()?).ok()?;
+        let expires_at = parts.next()?;
+        let subject = parts.next()?;
+
+        let payload = format!("{}.{}", subject, expires_at);
+        self.mac(&payload).verify(&signature).ok()?;
+        let expires_at = UNIX_EPOCH + Duration::from_secs(expires_at.parse().ok()?);
+        if expires_at <= SystemTime::now() {
+            return None;
+        }
+        Some(subject.into())
+    }
+}
+
+/// Accept a token if any of the verifiers accepts it.
+#[derive(Default)]
+pub struct AnyOf(pub Vec<Box<dyn TokenVerifier>>);
+
+impl TokenVerifier for AnyOf {
+    fn verify(&self, token: &str) -> Option<String> {
+        self.0.iter().find_map(|verifier| verifier.verify(token))
+    }
+}
+
+/// Interceptor rejecting requests without a valid `authorization: Bearer
+/// <token>` metadata with `Status::unauthenticated`. On success, the token
+/// subject is put in the request extensions.
+#[derive(Clone)]
+pub struct Authenticator {
+    // no verifier means authentication is disabled.
+    verifier: Option<Arc<dyn TokenVerifier>>,
+}
+
+impl Authenticator {
+    pub fn new(verifier: impl TokenVerifier) -> Self {
+        Authenticator {
+            verifier: Some(Arc::new(verifier)),
+        }
+    }
+
+    /// let every request through, authenticated or not.
+    pub fn disabled() -> Self {
+        Authenticator { verifier: None }
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let verifier = match &self.verifier {
+            Some(verifier) => verifier,
+            None => return Ok(request),
+        };
+        let header = match request.metadata().get("authorization") {
+            Some(header) => header,
+            None => return Err(Status::unauthenticated("missing bearer token")),
+        };
+        let token = header
+            .to_str()
+            .ok()
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("malformed bearer token"))?;
+        let subject = verifier
+            .verify(token)
+            .ok_or_else(|| Status::unauthenticated("invalid bearer token"))?;
+        request.extensions_mut().insert(Subject(subject));
+        Ok(request)
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[test]
+    // checks static tokens.
+    fn static_tokens() {
+        let verifier = StaticTokens::new().add("secret".into(), "alice".into());
+        assert_eq!(Some("alice".into()), verifier.verify("secret"));
+        assert_eq!(None, verifier.verify("Secret"));
+        assert_eq!(None, verifier.verify(""));
+    }
+
+    #[test]
+    // checks signed tokens.
+    fn hmac_tokens() {
+        let verifier = HmacTokens::new(b"key".to_vec());
+        let token = verifier.sign("bob.smith", HOUR);
+        assert_eq!(Some("bob.smith".into()), verifier.verify(&token));
+
+        // another key, a tampered subject or an expired token are refused.
+        let other = HmacTokens::new(b"other key".to_vec());
+        assert_eq!(None, other.verify(&token));
+        let tampered = token.replacen("bob", "eve", 1);
+        assert_eq!(None, verifier.verify(&tampered));
+        let expired = verifier.sign("bob", Duration::from_secs(0));
+        assert_eq!(None, verifier.verify(&expired));
+        assert_eq!(None, verifier.verify("garbage"));
+        assert_eq!(None, verifier.verify("a.b.c"));
+    }
+
+    #[test]
+    // checks a token is accepted by any verifier.
+    fn any_of() {
+        let hmac = HmacTokens::new(b"key".to_vec());
+        let token = hmac.sign("bob", HOUR);
+        let verifier = AnyOf(vec![
+            Box::new(StaticTokens::new().add("secret".into(), "alice".into())),
+            Box::new(hmac),
+        ]);
+        assert_eq!(Some("alice".into()), verifier.verify("secret"));
+        assert_eq!(Some("bob".into()), verifier.verify(&token));
+        assert_eq!(None, verifier.verify("other"));
+        assert_eq!(None, AnyOf::default().verify("secret"));
+    }
+
+    #[test]
+    // checks the interceptor only lets authenticated requests through.
+    fn authenticator() {
+        let mut authenticator =
+            Authenticator::new(StaticTokens::new().add("secret".into(), "alice".into()));
+        let request = |header: Option<&'static str>| {
+            let mut request = Request::new(());
+            if let Some(header) = header {
+                request
+                    .metadata_mut()
+                    .insert("authorization", header.parse().unwrap());
+            }
+            request
+        };
+
+        let accepted = authenticator.call(request(Some("Bearer secret"))).unwrap();
+        assert_eq!(
+            Some(&Subject("alice".into())),
+            accepted.extensions().get::<Subject>()
+        );
+        for header in [None, Some("secret"), Some("Bearer other")].iter() {
+            let status = authenticator.call(request(*header)).unwrap_err();
+            assert_eq!(tonic::Code::Unauthenticated, status.code());
+        }
+
+        let mut disabled = Authenticator::disabled();
+        let accepted = disabled.call(request(None)).unwrap();
+        assert_eq!(None, accepted.extensions().get::<Subject>());
+    }
+}
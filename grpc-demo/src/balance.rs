@@ -0,0 +1,603 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tonic::transport::Channel;
+use tonic::Status;
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::HealthCheckRequest;
+use tower::{Service, ServiceExt};
+
+use crate::echo::v1::echo_service_server::SERVICE_NAME;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Build the channel to an endpoint, given its uri.
+pub type Connect = Arc<dyn Fn(&str) -> Result<Channel, BoxError> + Send + Sync>;
+
+/// How the calls are spread over the endpoints.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Balancing {
+    /// each endpoint in turn.
+    #[default]
+    RoundRobin,
+    /// the endpoint with the fewest calls running.
+    LeastLoaded,
+}
+
+impl FromStr for Balancing {
+    type Err = String;
+
+    fn from_str(balancing: &str) -> Result<Self, Self::Err> {
+        match balancing {
+            "round-robin" => Ok(Balancing::RoundRobin),
+            "least-loaded" => Ok(Balancing::LeastLoaded),
+            _ => Err(format!(
+                "unknown balancing {:?}, expected round-robin or least-loaded",
+                balancing
+            )),
+        }
+    }
+}
+
+/// Servers of a target: urls or unix sockets separated by commas, or
+/// `dns:<host>:<port>` for every address of a host.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoints {
+    Static(Vec<String>),
+    Dns { host: String, port: u16 },
+}
+
+impl FromStr for Endpoints {
+    type Err = String;
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        let name = match target.strip_prefix("dns:") {
+            Some(name) => name,
+            None => {
+                let uris = target
+                    .split(',')
+                    .map(|uri| uri.trim().to_string())
+                    .filter(|uri| !uri.is_empty())
+                    .collect::<Vec<_>>();
+                if uris.is_empty() {
+                    return Err("no server to call".into());
+                }
+                return Ok(Endpoints::Static(uris));
+            }
+        };
+        // like gRPC names, the authority is optional and ignored: `dns:///host:port`.
+        let name = name.strip_prefix("///").unwrap_or(name);
+        let invalid = || {
+            format!(
+                "invalid DNS target {:?}, expected dns:<host>:<port>",
+                target
+            )
+        };
+        let (host, port) = name.rsplit_once(':').ok_or_else(invalid)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Endpoints::Dns {
+            host: host.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Endpoints {
+    /// the uris of the endpoints, calling them with a scheme if resolved.
+    async fn resolve(&self, scheme: &str) -> std::io::Result<Vec<String>> {
+        match self {
+            Endpoints::Static(uris) => Ok(uris.clone()),
+            Endpoints::Dns { host, port } => {
+                let mut uris = tokio::net::lookup_host((host.as_str(), *port))
+                    .await?
+                    .map(|addr| format!("{}://{}", scheme, addr))
+                    .collect::<Vec<_>>();
+                uris.sort();
+                uris.dedup();
+                Ok(uris)
+            }
+        }
+    }
+}
+
+/// How often the endpoints are health checked, and their DNS name resolved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HealthCheck {
+    pub interval: Duration,
+}
+
+/// A server of the target.
+#[derive(Debug)]
+struct Backend {
+    uri: String,
+    channel: Channel,
+    // whether it is not ejected.
+    healthy: AtomicBool,
+    // number of calls running on it.
+    in_flight: AtomicUsize,
+}
+
+impl Backend {
+    fn new(uri: String, channel: Channel) -> Self {
+        Backend {
+            uri,
+            channel,
+            healthy: AtomicBool::new(true),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl PartialEq for Backend {
+    fn eq(&self, other: &Self) -> bool {
+        self.uri == other.uri
+    }
+}
+
+// the backends, once resolved.
+type Backends = Option<Arc<Vec<Arc<Backend>>>>;
+
+/// Channel spreading the calls over the endpoints of a target. Endpoints the
+/// health service doesn't report as serving are ejected until they are back;
+/// with every endpoint ejected, calls are still spread over all of them. Cheap
+/// to clone: every clone shares the same endpoints.
+#[derive(Debug, Clone)]
+pub struct BalancedChannel {
+    backends: watch::Receiver<Backends>,
+    balancing: Balancing,
+    // start of the next round-robin.
+    next: Arc<AtomicUsize>,
+    // ends the health checks when the last clone is dropped.
+    _updates: Option<Arc<watch::Sender<Backends>>>,
+}
+
+impl BalancedChannel {
+    /// channel to a single endpoint, never ejected.
+    pub fn single(channel: Channel) -> Self {
+        let backend = Backend::new(String::new(), channel);
+        let (_, backends) = watch::channel(Some(Arc::new(vec![Arc::new(backend)])));
+        BalancedChannel {
+            backends,
+            balancing: Balancing::default(),
+            next: Arc::new(AtomicUsize::new(0)),
+            _updates: None,
+        }
+    }
+
+    /// channel to the endpoints of a target, connected with a scheme if
+    /// resolved from DNS. It must be created within a Tokio runtime, which
+    /// runs the health checks and DNS resolutions in the background.
+    pub fn new(
+        endpoints: Endpoints,
+        scheme: &'static str,
+        connect: Connect,
+        balancing: Balancing,
+        health_check: HealthCheck,
+    ) -> Result<Self, BoxError> {
+        // static endpoints can be called right away.
+        let initial = match &endpoints {
+            Endpoints::Static(uris) => {
+                let mut backends = Vec::new();
+                for uri in uris.iter() {
+                    backends.push(Arc::new(Backend::new(uri.clone(), connect(uri)?)));
+                }
+                Some(Arc::new(backends))
+            }
+            Endpoints::Dns { .. } => None,
+        };
+        let (updates, backends) = watch::channel(initial);
+        let updates = Arc::new(updates);
+        tokio::spawn(watch_endpoints(
+            Arc::downgrade(&updates),
+            endpoints,
+            scheme,
+            connect,
+            health_check,
+        ));
+        Ok(BalancedChannel {
+            backends,
+            balancing,
+            next: Arc::new(AtomicUsize::new(0)),
+            _updates: Some(updates),
+        })
+    }
+}
+
+/// whether an endpoint reports the echo service as serving.
+async fn is_serving(channel: Channel, timeout: Duration) -> bool {
+    let request = HealthCheckRequest {
+        service: SERVICE_NAME.into(),
+    };
+    let mut client = HealthClient::new(channel);
+    match tokio::time::timeout(timeout, client.check(request)).await {
+        Ok(Ok(response)) => response.into_inner().status == ServingStatus::Serving as i32,
+        _ => false,
+    }
+}
+
+/// health check backends, and eject the unhealthy ones.
+async fn check_health(backends: &[Arc<Backend>], timeout: Duration) {
+    let mut checks = JoinSet::new();
+    for backend in backends.iter().cloned() {
+        checks.spawn(async move {
+            let healthy = is_serving(backend.channel.clone(), timeout).await;
+            let was_healthy = backend.healthy.swap(healthy, Ordering::Relaxed);
+            match (was_healthy, healthy) {
+                (true, false) => {
+                    tracing::warn!(endpoint = %backend.uri, "endpoint ejected, not serving")
+                }
+                (false, true) => tracing::info!(endpoint = %backend.uri, "endpoint serving again"),
+                _ => {}
+            }
+        });
+    }
+    while checks.join_next().await.is_some() {}
+}
+
+/// resolve the endpoints and health check them periodically, until the
+/// channel is dropped.
+async fn watch_endpoints(
+    updates: std::sync::Weak<watch::Sender<Backends>>,
+    endpoints: Endpoints,
+    scheme: &'static str,
+    connect: Connect,
+    health_check: HealthCheck,
+) {
+    loop {
+        let updates = match updates.upgrade() {
+            Some(updates) => updates,
+            None => return,
+        };
+        let current = updates.borrow().clone().unwrap_or_default();
+        let backends = match endpoints.resolve(scheme).await {
+            Ok(uris) => {
+                let mut known = current
+                    .iter()
+                    .map(|backend| (backend.uri.clone(), backend.clone()))
+                    .collect::<HashMap<_, _>>();
+                let mut backends = Vec::new();
+                for uri in uris {
+                    if let Some(backend) = known.remove(&uri) {
+                        backends.push(backend);
+                        continue;
+                    }
+                    match connect(&uri) {
+                        Ok(channel) => backends.push(Arc::new(Backend::new(uri, channel))),
+                        Err(err) => {
+                            tracing::warn!(endpoint = %uri, error = %err, "invalid endpoint")
+                        }
+                    }
+                }
+                backends
+            }
+            Err(err) => {
+                tracing::warn!(error = %err, "cannot resolve the endpoints");
+                current.to_vec()
+            }
+        };
+
+        // new endpoints are checked before being called.
+        check_health(&backends, health_check.interval).await;
+        let backends = Arc::new(backends);
+        updates.send_if_modified(|current| match current {
+            Some(current) if **current == *backends => false,
+            _ => {
+                *current = Some(backends);
+                true
+            }
+        });
+        drop(updates);
+        tokio::time::sleep(health_check.interval).await;
+    }
+}
+
+/// pick the backend of the next call, out of the healthy ones if any.
+fn pick(
+    backends: &[Arc<Backend>],
+    balancing: Balancing,
+    next: &AtomicUsize,
+) -> Option<Arc<Backend>> {
+    let healthy = backends
+        .iter()
+        .filter(|backend| backend.healthy.load(Ordering::Relaxed))
+        .collect::<Vec<_>>();
+    // calling ejected endpoints is better than failing for sure.
+    let candidates = if healthy.is_empty() {
+        backends.iter().collect()
+    } else {
+        healthy
+    };
+    if candidates.is_empty() {
+        return None;
+    }
+    let start = next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+    let in_turn = candidates.iter().cycle().skip(start).take(candidates.len());
+    let backend = match balancing {
+        Balancing::RoundRobin => candidates[start],
+        // starting from the next one in turn spreads the ties.
+        Balancing::LeastLoaded => in_turn
+            .min_by_key(|backend| backend.in_flight.load(Ordering::Relaxed))
+            .unwrap(),
+    };
+    Some(backend.clone())
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl Service<http::Request<tonic::body::Body>> for BalancedChannel {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = BoxError;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the endpoint is picked once the call is made, and waited for then.
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<tonic::body::Body>) -> Self::Future {
+        let mut backends = self.backends.clone();
+        let balancing = self.balancing;
+        let next = self.next.clone();
+        Box::pin(async move {
+            // DNS names are resolved in the background.
+            let backends = backends
+                .wait_for(Option::is_some)
+                .await
+                .map_err(|_| Status::unavailable("endpoints not resolved"))?
+                .clone()
+                .unwrap_or_default();
+            let backend = pick(&backends, balancing, &next)
+                .ok_or_else(|| Status::unavailable("no endpoint to call"))?;
+
+            backend.in_flight.fetch_add(1, Ordering::Relaxed);
+            let load = Load(backend.clone());
+            let response = backend.channel.clone().oneshot(request).await?;
+            Ok(response.map(|body| tonic::body::Body::new(LoadedBody { body, _load: load })))
+        })
+    }
+}
+
+/// A call running on a backend, until dropped.
+struct Load(Arc<Backend>);
+
+impl Drop for Load {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Response body counting as a call running on its backend, until dropped.
+struct LoadedBody {
+    body: tonic::body::Body,
+    _load: Load,
+}
+
+impl Body for LoadedBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echo::v1::echo_service_client::EchoServiceClient;
+    use crate::echo::v1::echo_service_server::EchoServiceServer;
+    use crate::echo::v1::SayRequest;
+    use crate::mock::MockEcho;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+    use tonic::transport::{Endpoint, Server};
+    use tonic_health::server::HealthReporter;
+
+    const INTERVAL: Duration = Duration::from_millis(50);
+
+    #[test]
+    // checks targets parsing.
+    fn endpoints() {
+        assert_eq!(
+            Ok(Endpoints::Static(vec!["http://[::1]:50051".into()])),
+            "http://[::1]:50051".parse()
+        );
+        assert_eq!(
+            Ok(Endpoints::Static(vec![
+                "http://a:1".into(),
+                "unix:/tmp/echo.sock".into()
+            ])),
+            "http://a:1, unix:/tmp/echo.sock".parse()
+        );
+        for target in ["dns:echo:50051", "dns:///echo:50051"].iter() {
+            assert_eq!(
+                Ok(Endpoints::Dns {
+                    host: "echo".into(),
+                    port: 50051
+                }),
+                target.parse()
+            );
+        }
+        for target in ["", "dns:echo", "dns::50051", "dns:echo:http"].iter() {
+            assert!(target.parse::<Endpoints>().is_err());
+        }
+        assert_eq!(Ok(Balancing::LeastLoaded), "least-loaded".parse());
+        assert!("random".parse::<Balancing>().is_err());
+    }
+
+    /// A local echo server, counting its calls.
+    struct Instance {
+        uri: String,
+        mock: MockEcho,
+        health: HealthReporter,
+    }
+
+    impl Instance {
+        async fn spawn() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mock = MockEcho::new();
+            let (health, health_service) = tonic_health::server::health_reporter();
+            health
+                .set_service_status(SERVICE_NAME, tonic_health::ServingStatus::Serving)
+                .await;
+            tokio::spawn(
+                Server::builder()
+                    .add_service(health_service)
+                    .add_service(EchoServiceServer::new(mock.clone()))
+                    .serve_with_incoming(TcpListenerStream::new(listener)),
+            );
+            Instance {
+                uri: format!("http://{}", addr),
+                mock,
+                health,
+            }
+        }
+
+        async fn set_serving(&self, serving: bool) {
+            let status = if serving {
+                tonic_health::ServingStatus::Serving
+            } else {
+                tonic_health::ServingStatus::NotServing
+            };
+            self.health.set_service_status(SERVICE_NAME, status).await;
+        }
+
+        fn calls(&self) -> usize {
+            self.mock.calls().len()
+        }
+    }
+
+    fn connect() -> Connect {
+        Arc::new(|uri: &str| -> Result<Channel, BoxError> {
+            Ok(Endpoint::from_shared(uri.to_string())?.connect_lazy())
+        })
+    }
+
+    /// spawn three instances, and a client balancing over them.
+    async fn spawn_instances(
+        balancing: Balancing,
+    ) -> (Vec<Instance>, EchoServiceClient<BalancedChannel>) {
+        let mut instances = Vec::new();
+        for _ in 0..3 {
+            instances.push(Instance::spawn().await);
+        }
+        let uris = instances.iter().map(|instance| instance.uri.clone());
+        let channel = BalancedChannel::new(
+            Endpoints::Static(uris.collect()),
+            "http",
+            connect(),
+            balancing,
+            HealthCheck { interval: INTERVAL },
+        )
+        .unwrap();
+        (instances, EchoServiceClient::new(channel))
+    }
+
+    async fn say(client: &mut EchoServiceClient<BalancedChannel>, count: usize) {
+        for _ in 0..count {
+            let request = SayRequest {
+                message: "Tonic".into(),
+            };
+            client.say(request).await.unwrap();
+        }
+    }
+
+    fn calls(instances: &[Instance]) -> Vec<usize> {
+        instances.iter().map(Instance::calls).collect()
+    }
+
+    #[tokio::test]
+    // checks calls are spread in turn over three instances, skipping the ones
+    // not serving until they are back.
+    async fn round_robin() {
+        let (instances, mut client) = spawn_instances(Balancing::RoundRobin).await;
+        say(&mut client, 30).await;
+        assert_eq!(vec![10, 10, 10], calls(&instances));
+
+        instances[1].set_serving(false).await;
+        tokio::time::sleep(INTERVAL * 4).await;
+        say(&mut client, 20).await;
+        assert_eq!(vec![20, 10, 20], calls(&instances));
+
+        instances[1].set_serving(true).await;
+        tokio::time::sleep(INTERVAL * 4).await;
+        say(&mut client, 30).await;
+        assert_eq!(vec![30, 20, 30], calls(&instances));
+
+        // with no instance serving, calls still go through.
+        for instance in instances.iter() {
+            instance.set_serving(false).await;
+        }
+        tokio::time::sleep(INTERVAL * 4).await;
+        say(&mut client, 3).await;
+        assert_eq!(83, calls(&instances).iter().sum::<usize>());
+    }
+
+    #[tokio::test]
+    // checks calls go to the instance with the fewest calls running.
+    async fn least_loaded() {
+        let (instances, mut client) = spawn_instances(Balancing::LeastLoaded).await;
+        let mut chats = Vec::new();
+        for _ in 0..2 {
+            let (requests, rx) = mpsc::channel::<SayRequest>(1);
+            let responses = client.chat(ReceiverStream::new(rx)).await.unwrap();
+            chats.push((requests, responses));
+        }
+        say(&mut client, 3).await;
+        // the two chats run on two instances, the calls on the third one.
+        let mut calls = calls(&instances);
+        calls.sort_unstable();
+        assert_eq!(vec![1, 1, 3], calls);
+    }
+
+    #[tokio::test]
+    // checks a DNS name is resolved to its addresses.
+    async fn dns() {
+        let instance = Instance::spawn().await;
+        let port = instance.uri.rsplit(':').next().unwrap().parse().unwrap();
+        let channel = BalancedChannel::new(
+            Endpoints::Dns {
+                host: "localhost".into(),
+                port,
+            },
+            "http",
+            connect(),
+            Balancing::RoundRobin,
+            HealthCheck { interval: INTERVAL },
+        )
+        .unwrap();
+        // localhost may also resolve to an IPv6 address nobody listens on,
+        // which is ejected.
+        say(&mut EchoServiceClient::new(channel), 3).await;
+        assert_eq!(3, instance.calls());
+    }
+}
@@ -0,0 +1,525 @@
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::{KeyAndValueRef, MetadataKey, MetadataMap, MetadataValue};
+use tonic::{Request, Status, Streaming};
+
+use grpc_demo::client_config::ClientConfig;
+use grpc_demo::echo::v1::{SayManyRequest, SayRequest, SayResponse};
+use grpc_demo::propagation::REQUEST_ID;
+use grpc_demo::retry::{retry, Client};
+use grpc_demo::validate::field_violations;
+
+const HELP: &str = "\
+Type a message to send it, or a command:
+  /mode say|chat          send the messages with Say, or on a Chat stream
+  /many <count> <message> have the message answered several times, with SayMany
+  /collect <a> | <b> ...  send several messages, answered at once, with CollectSay
+  /target <uri>           connect to another server, e.g. http://[::1]:50051
+  /header <name> [value]  send a metadata header, or stop sending it
+  /header                 list the headers sent
+  /deadline <ms>|off      set or remove the deadline of each message
+  /help                   show this help
+  /quit                   leave";
+
+/// How messages are sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// one `Say` call per message, retried.
+    Say,
+    /// every message on the same `Chat` stream.
+    Chat,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "say" => Ok(Mode::Say),
+            "chat" => Ok(Mode::Chat),
+            _ => Err(format!("unknown mode {:?}, expected say or chat", mode)),
+        }
+    }
+}
+
+/// A line typed in the shell.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Send(String),
+    Many(u32, String),
+    Collect(Vec<String>),
+    Mode(Mode),
+    Target(String),
+    Header(String, Option<String>),
+    Headers,
+    Deadline(Option<Duration>),
+    Help,
+    Quit,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let command = match line.strip_prefix('/') {
+            Some(command) => command,
+            None => return Ok(Command::Send(line.to_string())),
+        };
+        let args = command.split_whitespace().collect::<Vec<_>>();
+        match args.as_slice() {
+            ["mode", mode] => Ok(Command::Mode(mode.parse()?)),
+            ["many", count, message @ ..] => match count.parse() {
+                Ok(count) => Ok(Command::Many(count, message.join(" "))),
+                Err(_) => Err(format!("invalid count {:?}, expected a number", count)),
+            },
+            ["collect", ..] => Ok(Command::Collect(
+                command["collect".len()..]
+                    .split('|')
+                    .map(|message| message.trim().to_string())
+                    .collect(),
+            )),
+            ["target", target] => Ok(Command::Target(target.to_string())),
+            ["header"] => Ok(Command::Headers),
+            ["header", name] => Ok(Command::Header(name.to_lowercase(), None)),
+            ["header", name, value @ ..] => {
+                Ok(Command::Header(name.to_lowercase(), Some(value.join(" "))))
+            }
+            ["deadline", "off"] => Ok(Command::Deadline(None)),
+            ["deadline", ms] => match ms.parse() {
+                Ok(ms) if ms > 0 => Ok(Command::Deadline(Some(Duration::from_millis(ms)))),
+                _ => Err(format!("invalid deadline {:?}, expected milliseconds", ms)),
+            },
+            ["help"] => Ok(Command::Help),
+            ["quit"] | ["exit"] => Ok(Command::Quit),
+            _ => Err(format!("unknown command {:?}, see /help", line)),
+        }
+    }
+}
+
+/// A `Chat` stream, kept open between messages.
+struct Chat {
+    requests: mpsc::Sender<SayRequest>,
+    responses: Streaming<SayResponse>,
+}
+
+/// State of the shell.
+struct Session {
+    config: ClientConfig,
+    client: Client,
+    mode: Mode,
+    headers: MetadataMap,
+    deadline: Option<Duration>,
+    chat: Option<Chat>,
+}
+
+/// describe metadata, one indented entry per line.
+fn describe_metadata(metadata: &MetadataMap) -> String {
+    let mut text = String::new();
+    for entry in metadata.iter() {
+        // writing to a string never fails.
+        let _ = match entry {
+            KeyAndValueRef::Ascii(key, value) => write!(text, "\n  {}: {:?}", key, value),
+            KeyAndValueRef::Binary(key, value) => write!(text, "\n  {}: {:?}", key, value),
+        };
+    }
+    text
+}
+
+/// describe a response, its latency and its metadata.
+fn describe(message: &str, latency: Duration, metadata: &MetadataMap) -> String {
+    format!(
+        "{}  ({:.2?}){}",
+        message,
+        latency,
+        describe_metadata(metadata)
+    )
+}
+
+/// describe a failed call, with the invalid fields and the request id if
+/// any, to look the call up in the server logs.
+fn describe_error(status: &Status) -> String {
+    let mut text = format!("error: {:?}: {}", status.code(), status.message());
+    for violation in field_violations(status) {
+        text.push_str(&format!("\n  invalid {}", violation));
+    }
+    if let Some(request_id) = status.metadata().get(REQUEST_ID) {
+        text.push_str(&format!("\n  request id: {:?}", request_id));
+    }
+    text
+}
+
+impl Session {
+    fn new(config: ClientConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let client = config.client()?.client();
+        Ok(Session {
+            deadline: config.deadline,
+            config,
+            client,
+            mode: Mode::Say,
+            headers: MetadataMap::new(),
+            chat: None,
+        })
+    }
+
+    fn prompt(&self) -> String {
+        let mode = match self.mode {
+            Mode::Say => "say",
+            Mode::Chat => "chat",
+        };
+        format!("{}@{}> ", mode, self.config.target)
+    }
+
+    /// build a request carrying the headers, and the deadline.
+    fn request<T>(&self, message: T, timeout: Option<Duration>) -> Request<T> {
+        let mut request = Request::new(message);
+        *request.metadata_mut() = self.headers.clone();
+        if let Some(timeout) = timeout {
+            request.set_timeout(timeout);
+        }
+        request
+    }
+
+    /// run a command, and return what to show.
+    async fn execute(&mut self, command: Command) -> Result<String, String> {
+        match command {
+            Command::Send(message) => match self.mode {
+                Mode::Say => self.say(message).await,
+                Mode::Chat => self.chat(message).await,
+            },
+            Command::Many(count, message) => self.say_many(count, message).await,
+            Command::Collect(messages) => self.collect(messages).await,
+            Command::Mode(mode) => {
+                self.mode = mode;
+                self.chat = None;
+                Ok(String::new())
+            }
+            Command::Target(target) => {
+                let mut config = self.config.clone();
+                config.target = target;
+                self.client = config
+                    .client()
+                    .map_err(|err| format!("invalid target: {}", err))?
+                    .client();
+                self.config = config;
+                self.chat = None;
+                Ok(String::new())
+            }
+            Command::Header(name, value) => {
+                let key = MetadataKey::from_str(&name)
+                    .map_err(|_| format!("invalid header name {:?}", name))?;
+                match value {
+                    Some(value) => {
+                        let value = MetadataValue::from_str(&value)
+                            .map_err(|_| format!("invalid header value {:?}", value))?;
+                        self.headers.insert(key, value);
+                    }
+                    None => {
+                        self.headers.remove(key);
+                    }
+                }
+                // the stream was opened with the previous headers.
+                self.chat = None;
+                Ok(String::new())
+            }
+            Command::Headers if self.headers.is_empty() => Ok("no headers".into()),
+            Command::Headers => Ok(format!("headers:{}", describe_metadata(&self.headers))),
+            Command::Deadline(deadline) => {
+                self.deadline = deadline;
+                Ok(String::new())
+            }
+            Command::Help => Ok(HELP.to_string()),
+            Command::Quit => Ok(String::new()),
+        }
+    }
+
+    async fn say(&mut self, message: String) -> Result<String, String> {
+        let started_at = Instant::now();
+        let response = retry(&self.config.retry, self.deadline, |timeout| {
+            let mut client = self.client.clone();
+            let request = self.request(
+                SayRequest {
+                    message: message.clone(),
+                },
+                timeout,
+            );
+            async move { client.say(request).await }
+        })
+        .await
+        .map_err(|status| describe_error(&status))?;
+        let latency = started_at.elapsed();
+        Ok(describe(
+            &response.get_ref().message,
+            latency,
+            response.metadata(),
+        ))
+    }
+
+    /// have a message answered `count` times, one answer per line.
+    async fn say_many(&mut self, count: u32, message: String) -> Result<String, String> {
+        let started_at = Instant::now();
+        let request = self.request(SayManyRequest { message, count }, self.deadline);
+        let response = self
+            .client
+            .clone()
+            .say_many(request)
+            .await
+            .map_err(|status| describe_error(&status))?;
+        let metadata = response.metadata().clone();
+        let mut stream = response.into_inner();
+        let mut answers = Vec::new();
+        while let Some(response) = stream
+            .message()
+            .await
+            .map_err(|status| describe_error(&status))?
+        {
+            answers.push(response.message);
+        }
+        Ok(describe(
+            &answers.join("\n"),
+            started_at.elapsed(),
+            &metadata,
+        ))
+    }
+
+    /// send several messages on one stream, and wait for their single answer.
+    async fn collect(&mut self, messages: Vec<String>) -> Result<String, String> {
+        let started_at = Instant::now();
+        let requests = messages
+            .into_iter()
+            .map(|message| SayRequest { message })
+            .collect::<Vec<_>>();
+        let request = self.request(tokio_stream::iter(requests), self.deadline);
+        let response = self
+            .client
+            .clone()
+            .collect_say(request)
+            .await
+            .map_err(|status| describe_error(&status))?;
+        Ok(describe(
+            &response.get_ref().message,
+            started_at.elapsed(),
+            response.metadata(),
+        ))
+    }
+
+    /// send a message on the chat stream, opening it first if needed, and
+    /// wait for its answer.
+    async fn chat(&mut self, message: String) -> Result<String, String> {
+        let mut opened = None;
+        if self.chat.is_none() {
+            let (requests, rx) = mpsc::channel(1);
+            let request = self.request(ReceiverStream::new(rx), None);
+            let response = self
+                .client
+                .clone()
+                .chat(request)
+                .await
+                .map_err(|status| describe_error(&status))?;
+            opened = Some(response.metadata().clone());
+            self.chat = Some(Chat {
+                requests,
+                responses: response.into_inner(),
+            });
+        }
+        let deadline = self.deadline;
+        // the stream was just opened if missing.
+        let chat = self.chat.as_mut().unwrap();
+
+        let started_at = Instant::now();
+        let answer = async {
+            chat.requests
+                .send(SayRequest { message })
+                .await
+                .map_err(|_| Status::unavailable("chat closed"))?;
+            chat.responses
+                .message()
+                .await?
+                .ok_or_else(|| Status::unavailable("chat closed by the server"))
+        };
+        let answer = match deadline {
+            Some(deadline) => match tokio::time::timeout(deadline, answer).await {
+                Ok(answer) => answer,
+                Err(_) => Err(Status::deadline_exceeded("message deadline exceeded")),
+            },
+            None => answer.await,
+        };
+        match answer {
+            Ok(response) => Ok(describe(
+                &response.message,
+                started_at.elapsed(),
+                &opened.unwrap_or_default(),
+            )),
+            Err(status) => {
+                // a new stream is opened for the next message.
+                self.chat = None;
+                Err(describe_error(&status))
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut session = Session::new(ClientConfig::load()?)?;
+    let mut editor = DefaultEditor::new()?;
+    println!("{}", HELP);
+
+    loop {
+        let prompt = session.prompt();
+        // reading the terminal blocks, but the connection runs on the other
+        // worker threads.
+        let line = match tokio::task::block_in_place(|| editor.readline(&prompt)) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+
+        let command = match line.parse() {
+            Ok(Command::Quit) => break,
+            Ok(command) => command,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+        match session.execute(command).await {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+
+    Ok(())
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grpc_demo::auth::Authenticator;
+    use grpc_demo::client_config::ClientOpt;
+    use grpc_demo::echo::v1::echo_service_server::EchoServiceServer;
+    use grpc_demo::propagation::PropagationLayer;
+    use grpc_demo::service::{self, MyEchoService};
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+
+    #[test]
+    // checks commands parsing.
+    fn commands() {
+        assert_eq!(Ok(Command::Send("hello".into())), "hello".parse());
+        assert_eq!(Ok(Command::Mode(Mode::Chat)), "/mode chat".parse());
+        assert_eq!(
+            Ok(Command::Many(3, "hello there".into())),
+            "/many 3 hello there".parse()
+        );
+        assert_eq!(
+            Ok(Command::Collect(vec!["a".into(), "b c".into()])),
+            "/collect a | b c".parse()
+        );
+        assert_eq!(
+            Ok(Command::Target("http://127.0.0.1:1234".into())),
+            "/target http://127.0.0.1:1234".parse()
+        );
+        assert_eq!(
+            Ok(Command::Header("x-tenant".into(), Some("acme corp".into()))),
+            "/header X-Tenant acme corp".parse()
+        );
+        assert_eq!(
+            Ok(Command::Header("x-tenant".into(), None)),
+            "/header x-tenant".parse()
+        );
+        assert_eq!(Ok(Command::Headers), "/header".parse());
+        assert_eq!(
+            Ok(Command::Deadline(Some(Duration::from_millis(250)))),
+            "/deadline 250".parse()
+        );
+        assert_eq!(Ok(Command::Deadline(None)), "/deadline off".parse());
+        assert_eq!(Ok(Command::Quit), "/quit".parse());
+
+        for line in [
+            "/mode sing",
+            "/many lots hi",
+            "/deadline 0",
+            "/deadline soon",
+            "/unknown",
+        ]
+        .iter()
+        {
+            assert!(line.parse::<Command>().is_err(), "{}", line);
+        }
+    }
+
+    async fn run(session: &mut Session, line: &str) -> Result<String, String> {
+        session.execute(line.parse().unwrap()).await
+    }
+
+    #[tokio::test]
+    // checks messages are sent with each mode, and headers are checked.
+    async fn session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::builder().layer(PropagationLayer);
+        let echo = EchoServiceServer::new(MyEchoService::default());
+        let (router, _health_reporter) =
+            service::add_services(&mut server, echo, Authenticator::disabled())
+                .await
+                .unwrap();
+        tokio::spawn(router.serve_with_incoming(TcpListenerStream::new(listener)));
+
+        let opt = ClientOpt {
+            target: Some(format!("http://{}", addr)),
+            ..Default::default()
+        };
+        let mut session = Session::new(ClientConfig::from_opt(opt).unwrap()).unwrap();
+        let session = &mut session;
+
+        let output = run(session, "hello").await.unwrap();
+        assert!(output.starts_with("Hello hello!"));
+        assert!(output.contains("x-request-id: "));
+        let output = run(session, "").await.unwrap_err();
+        assert!(output.contains("message: must not be empty"));
+        assert!(output.contains("request id: "));
+
+        run(session, "/header x-tenant acme").await.unwrap();
+        assert!(run(session, "/header")
+            .await
+            .unwrap()
+            .contains("x-tenant: \"acme\""));
+        assert!(run(session, "/header b@d x").await.is_err());
+
+        let output = run(session, "/many 2 hi").await.unwrap();
+        assert!(output.starts_with("Hello hi #0!\nHello hi #1!  ("));
+        assert!(run(session, "/many 2")
+            .await
+            .unwrap_err()
+            .contains("message: must not be empty"));
+        let output = run(session, "/collect a | b | c").await.unwrap();
+        assert!(output.starts_with("Hello a, b, c!"));
+
+        run(session, "/mode chat").await.unwrap();
+        for message in ["one", "two"].iter() {
+            let output = run(session, message).await.unwrap();
+            assert!(output.starts_with(&format!("Hello {}!", message)));
+        }
+        // an invalid message ends the chat, and the next one opens another.
+        assert!(run(session, "").await.is_err());
+        assert!(run(session, "three")
+            .await
+            .unwrap()
+            .starts_with("Hello three!"));
+    }
+}
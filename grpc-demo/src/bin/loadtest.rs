@@ -0,0 +1,432 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use structopt::StructOpt;
+use tokio::process::{Child, Command};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+use tonic::Status;
+
+use grpc_demo::client_config::{ClientConfig, ClientOpt};
+use grpc_demo::echo::v1::{SayManyRequest, SayRequest};
+use grpc_demo::retry::RetryingClient;
+
+// how long the local server has to start.
+const SERVER_STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Load test options. The client ones are the same as the client binary,
+/// except calls are never retried.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "loadtest", about = "Echo gRPC load generator")]
+struct LoadTestOpt {
+    #[structopt(flatten)]
+    client: ClientOpt,
+    /// Requests per second to send. Without it, each of the concurrent callers sends its next request as soon as the previous one is done
+    #[structopt(long)]
+    qps: Option<f64>,
+    /// Maximum number of requests running at the same time
+    #[structopt(long, default_value = "10")]
+    concurrency: usize,
+    /// Duration of the test, in seconds
+    #[structopt(long, default_value = "10")]
+    duration_secs: f64,
+    /// RPC to call: `say`, `say-many` or `chat`
+    #[structopt(long, default_value = "say")]
+    rpc: Rpc,
+    /// Size of the messages sent, in bytes. Servers refuse messages longer than their maximum message length, 1024 by default
+    #[structopt(long, default_value = "16")]
+    message_size: usize,
+    /// Number of messages of each say-many or chat call
+    #[structopt(long, default_value = "10")]
+    messages: u32,
+    /// Start the server built along this binary on a free local port, and test it
+    #[structopt(long)]
+    local_server: bool,
+    /// Argument of the local server, e.g. `--server-arg=--max-concurrent-requests=100`
+    #[structopt(long = "server-arg", number_of_values = 1, allow_hyphen_values = true)]
+    server_args: Vec<String>,
+}
+
+/// RPC driven by the load test.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rpc {
+    Say,
+    SayMany,
+    Chat,
+}
+
+impl FromStr for Rpc {
+    type Err = String;
+
+    fn from_str(rpc: &str) -> Result<Self, Self::Err> {
+        match rpc {
+            "say" => Ok(Rpc::Say),
+            "say-many" => Ok(Rpc::SayMany),
+            "chat" => Ok(Rpc::Chat),
+            _ => Err(format!(
+                "unknown rpc {:?}, expected say, say-many or chat",
+                rpc
+            )),
+        }
+    }
+}
+
+/// What is sent by each call.
+#[derive(Debug, Clone)]
+struct Load {
+    rpc: Rpc,
+    message: String,
+    messages: u32,
+}
+
+/// make a call, reading every response.
+async fn call(client: &RetryingClient, load: &Load) -> Result<(), Status> {
+    let mut stream = match load.rpc {
+        Rpc::Say => {
+            let request = SayRequest {
+                message: load.message.clone(),
+            };
+            return client.say(request).await.map(|_| ());
+        }
+        Rpc::SayMany => {
+            let request = SayManyRequest {
+                message: load.message.clone(),
+                count: load.messages,
+            };
+            client.say_many(request).await?
+        }
+        Rpc::Chat => {
+            let requests = (0..load.messages)
+                .map(|_| SayRequest {
+                    message: load.message.clone(),
+                })
+                .collect::<Vec<_>>();
+            client
+                .client()
+                .chat(tokio_stream::iter(requests))
+                .await?
+                .into_inner()
+        }
+    };
+    while let Some(response) = stream.next().await {
+        response?;
+    }
+    Ok(())
+}
+
+/// Outcome of the calls.
+#[derive(Debug, Default)]
+struct Stats {
+    // latency of the successful calls.
+    latencies: Vec<Duration>,
+    // number of failed calls per status code.
+    errors: BTreeMap<String, u64>,
+    // calls not sent, as the concurrency limit was reached.
+    skipped: u64,
+}
+
+impl Stats {
+    fn record(&mut self, latency: Duration, result: Result<(), Status>) {
+        match result {
+            Ok(()) => self.latencies.push(latency),
+            Err(status) => {
+                *self
+                    .errors
+                    .entry(format!("{:?}", status.code()))
+                    .or_insert(0) += 1
+            }
+        }
+    }
+
+    fn calls(&self) -> u64 {
+        self.latencies.len() as u64 + self.errors.values().sum::<u64>()
+    }
+
+    /// latency under which are `percent`% of the successful calls.
+    fn percentile(&mut self, percent: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        self.latencies.sort_unstable();
+        let rank = (percent / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        Some(self.latencies[rank.clamp(1, self.latencies.len()) - 1])
+    }
+
+    fn report(&mut self, elapsed: Duration) {
+        let calls = self.calls();
+        println!(
+            "calls:      {} in {:.1?} ({} ok, {} failed, {} skipped)",
+            calls,
+            elapsed,
+            self.latencies.len(),
+            calls - self.latencies.len() as u64,
+            self.skipped
+        );
+        println!(
+            "throughput: {:.1} calls/s",
+            calls as f64 / elapsed.as_secs_f64()
+        );
+        for percent in [50.0, 90.0, 99.0, 99.9, 100.0].iter() {
+            if let Some(latency) = self.percentile(*percent) {
+                println!("latency:    p{:<5} {:.2?}", percent, latency);
+            }
+        }
+        for (code, count) in self.errors.iter() {
+            println!("errors:     {} {}", code, count);
+        }
+    }
+}
+
+/// send calls as fast as `concurrency` callers can, until the end.
+async fn closed_loop(
+    client: RetryingClient,
+    load: Load,
+    concurrency: usize,
+    end: Instant,
+) -> Stats {
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let mut callers = JoinSet::new();
+    for _ in 0..concurrency {
+        let (client, load, stats) = (client.clone(), load.clone(), stats.clone());
+        callers.spawn(async move {
+            while Instant::now() < end {
+                let started_at = Instant::now();
+                let result = call(&client, &load).await;
+                stats.lock().unwrap().record(started_at.elapsed(), result);
+            }
+        });
+    }
+    while callers.join_next().await.is_some() {}
+    Arc::try_unwrap(stats).unwrap().into_inner().unwrap()
+}
+
+/// send a call every `period` until the end, whether the previous ones
+/// answered or not, with at most `concurrency` calls running.
+async fn open_loop(
+    client: RetryingClient,
+    load: Load,
+    period: Duration,
+    concurrency: usize,
+    end: Instant,
+) -> Stats {
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let running = Arc::new(Semaphore::new(concurrency));
+    let mut calls = JoinSet::new();
+    let mut ticks = tokio::time::interval(period);
+    while ticks.tick().await < end.into() {
+        let permit = match running.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                stats.lock().unwrap().skipped += 1;
+                continue;
+            }
+        };
+        let (client, load, stats) = (client.clone(), load.clone(), stats.clone());
+        calls.spawn(async move {
+            let started_at = Instant::now();
+            let result = call(&client, &load).await;
+            stats.lock().unwrap().record(started_at.elapsed(), result);
+            drop(permit);
+        });
+        // forget the finished calls as we go.
+        while calls.try_join_next().is_some() {}
+    }
+    while calls.join_next().await.is_some() {}
+    Arc::try_unwrap(stats).unwrap().into_inner().unwrap()
+}
+
+/// get the delay between two calls sent at `qps` calls per second.
+fn call_period(qps: f64) -> Result<Duration, String> {
+    match Duration::try_from_secs_f64(1.0 / qps) {
+        Ok(period) if qps > 0.0 && !period.is_zero() => Ok(period),
+        _ => Err(format!("the qps must be positive and finite, not {}", qps)),
+    }
+}
+
+/// get the duration of the test.
+fn test_duration(secs: f64) -> Result<Duration, String> {
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if !duration.is_zero() => Ok(duration),
+        _ => Err(format!("the duration must be positive, not {}", secs)),
+    }
+}
+
+/// start the server binary built along this one, on a free local port.
+/// Return it, with its address.
+async fn start_local_server(
+    args: &[String],
+) -> Result<(Child, String), Box<dyn std::error::Error>> {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let listen = format!("127.0.0.1:{}", port);
+    let path = std::env::current_exe()?.with_file_name("server");
+    let mut command = Command::new(&path);
+    command
+        .arg("--listen")
+        .arg(&listen)
+        .args(args)
+        .stdout(std::process::Stdio::null())
+        .kill_on_drop(true);
+    // logging every call would slow the server down.
+    if std::env::var_os("RUST_LOG").is_none() {
+        command.env("RUST_LOG", "warn");
+    }
+    let server = command
+        .spawn()
+        .map_err(|err| format!("cannot start {}: {}", path.display(), err))?;
+
+    let target = format!("http://{}", listen);
+    let started_at = Instant::now();
+    while tokio::net::TcpStream::connect(&listen).await.is_err() {
+        if started_at.elapsed() > SERVER_STARTUP_TIMEOUT {
+            return Err("the local server did not start".into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    Ok((server, target))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut opt = LoadTestOpt::from_args();
+    if opt.concurrency == 0 {
+        return Err("the concurrency must be positive".into());
+    }
+    let period = opt.qps.map(call_period).transpose()?;
+    let duration = test_duration(opt.duration_secs)?;
+
+    let mut server = None;
+    if opt.local_server {
+        let (child, target) = start_local_server(&opt.server_args).await?;
+        println!("server:     {} (pid {:?})", target, child.id());
+        opt.client.target = Some(target);
+        server = Some(child);
+    }
+
+    let mut config = ClientConfig::from_opt(opt.client)?;
+    // each call is measured as is: retries would hide failures, and add
+    // their backoff to the latencies.
+    config.retry.max_attempts = 1;
+    let client = config.client()?;
+    let load = Load {
+        rpc: opt.rpc,
+        message: "x".repeat(opt.message_size),
+        messages: opt.messages,
+    };
+
+    let started_at = Instant::now();
+    let end = started_at + duration;
+    let mut stats = match period {
+        Some(period) => open_loop(client, load, period, opt.concurrency, end).await,
+        None => closed_loop(client, load, opt.concurrency, end).await,
+    };
+    stats.report(started_at.elapsed());
+
+    if let Some(mut server) = server {
+        server.kill().await?;
+    }
+    Ok(())
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grpc_demo::auth::Authenticator;
+    use grpc_demo::echo::v1::echo_service_server::EchoServiceServer;
+    use grpc_demo::service::{self, MyEchoService};
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+
+    #[test]
+    // checks rpc names parsing.
+    fn rpc() {
+        assert_eq!(Ok(Rpc::Say), "say".parse());
+        assert_eq!(Ok(Rpc::SayMany), "say-many".parse());
+        assert_eq!(Ok(Rpc::Chat), "chat".parse());
+        assert!("sing".parse::<Rpc>().is_err());
+    }
+
+    #[test]
+    // checks the qps and duration are validated.
+    fn rates() {
+        assert_eq!(Ok(Duration::from_millis(250)), call_period(4.0));
+        assert!(call_period(0.0).is_err());
+        assert!(call_period(-1.0).is_err());
+        assert!(call_period(f64::NAN).is_err());
+        assert!(call_period(f64::INFINITY).is_err());
+        assert!(call_period(1e12).is_err());
+
+        assert_eq!(Ok(Duration::from_millis(1500)), test_duration(1.5));
+        assert!(test_duration(0.0).is_err());
+        assert!(test_duration(-1.0).is_err());
+        assert!(test_duration(f64::NAN).is_err());
+        assert!(test_duration(f64::INFINITY).is_err());
+    }
+
+    #[test]
+    // checks calls are counted, and latency percentiles.
+    fn stats() {
+        let mut stats = Stats::default();
+        assert_eq!(None, stats.percentile(50.0));
+        for ms in (1..=100).rev() {
+            stats.record(Duration::from_millis(ms), Ok(()));
+        }
+        stats.record(Duration::from_millis(1), Err(Status::unavailable("")));
+        stats.record(Duration::from_millis(1), Err(Status::unavailable("")));
+        stats.record(Duration::from_millis(1), Err(Status::internal("")));
+
+        assert_eq!(103, stats.calls());
+        assert_eq!(Some(Duration::from_millis(1)), stats.percentile(0.0));
+        assert_eq!(Some(Duration::from_millis(50)), stats.percentile(50.0));
+        assert_eq!(Some(Duration::from_millis(99)), stats.percentile(99.0));
+        assert_eq!(Some(Duration::from_millis(100)), stats.percentile(99.9));
+        assert_eq!(Some(Duration::from_millis(100)), stats.percentile(100.0));
+        let errors = stats
+            .errors
+            .iter()
+            .map(|(code, count)| (code.as_str(), *count))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![("Internal", 1), ("Unavailable", 2)], errors);
+    }
+
+    #[tokio::test]
+    // checks every rpc against an in-process echo server.
+    async fn in_process() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::builder();
+        let (router, _health_reporter) = service::add_services(
+            &mut server,
+            EchoServiceServer::new(MyEchoService::default()),
+            Authenticator::disabled(),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(router.serve_with_incoming(TcpListenerStream::new(listener)));
+
+        let opt = ClientOpt {
+            target: Some(format!("http://{}", addr)),
+            ..Default::default()
+        };
+        let client = ClientConfig::from_opt(opt).unwrap().client().unwrap();
+        for rpc in [Rpc::Say, Rpc::SayMany, Rpc::Chat].iter() {
+            let load = Load {
+                rpc: *rpc,
+                message: "x".repeat(16),
+                messages: 3,
+            };
+            let end = Instant::now() + Duration::from_millis(100);
+            let mut stats = closed_loop(client.clone(), load, 2, end).await;
+            assert!(stats.calls() > 0);
+            assert!(stats.errors.is_empty(), "{:?}", stats.errors);
+            assert!(stats.percentile(50.0).is_some());
+        }
+    }
+}
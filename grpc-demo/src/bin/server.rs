@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use grpc_demo::auth::HmacTokens;
+use grpc_demo::server_config::ServerConfig;
+use grpc_demo::{drain, service, telemetry};
+
+// validity of the tokens issued with --issue-token.
+const ISSUED_TOKEN_VALIDITY: Duration = Duration::from_secs(24 * 3600);
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = ServerConfig::load()?;
+    if let Some(subject) = &config.issue_token {
+        let hmac_key = config
+            .auth
+            .hmac_key
+            .as_ref()
+            .ok_or("no HMAC key to sign with")?;
+        let tokens = HmacTokens::new(hmac_key.as_bytes().to_vec());
+        println!("{}", tokens.sign(subject, ISSUED_TOKEN_VALIDITY));
+        return Ok(());
+    }
+
+    telemetry::init(config.log_format);
+    let deadline = config.shutdown_deadline;
+    let signal = async move {
+        drain::terminate_signal().await;
+        tracing::info!(?deadline, "shutting down, draining requests");
+    };
+    let cancelled = service::serve(&config, signal).await?;
+    for rpc in cancelled.iter() {
+        tracing::warn!(
+            method = %rpc.method,
+            running_for = ?rpc.running_for,
+            "cancelled, still running after the shutdown deadline"
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,554 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use structopt::StructOpt;
+use tonic::codec::CompressionEncoding;
+use tonic::metadata::AsciiMetadataValue;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Request, Status};
+
+use crate::balance::{BalancedChannel, Balancing, Connect, Endpoints, HealthCheck};
+use crate::compression;
+use crate::echo::v1::echo_service_client::EchoServiceClient;
+use crate::retry::{RetryPolicy, RetryingClient};
+
+/// Client options. Each one is taken, by order of priority, from the command
+/// line, the environment, the config file, or its default.
+#[derive(Debug, Default, StructOpt, Deserialize)]
+#[structopt(name = "client", about = "Echo gRPC client")]
+#[serde(deny_unknown_fields)]
+pub struct ClientOpt {
+    /// TOML config file, holding the same options (e.g. `target = "unix:/tmp/echo.sock"`)
+    #[structopt(long, env = "ECHO_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+    /// Server to call: an url, or `unix:<path>` for a unix socket. Calls are balanced over several servers separated by commas, or over every address of `dns:<host>:<port>` [default: http://[::1]:50051, or https:// with TLS]
+    #[structopt(long, env = "ECHO_TARGET")]
+    pub target: Option<String>,
+    /// Maximum duration to connect to the server, in milliseconds
+    #[structopt(long, env = "ECHO_CONNECT_TIMEOUT_MS")]
+    pub connect_timeout_ms: Option<u64>,
+    /// Maximum duration of a request, in milliseconds
+    #[structopt(long, env = "ECHO_TIMEOUT_MS")]
+    pub timeout_ms: Option<u64>,
+    /// Maximum duration of a call, retries included, in milliseconds
+    #[structopt(long, env = "ECHO_DEADLINE_MS")]
+    pub deadline_ms: Option<u64>,
+    /// Maximum number of attempts of a call [default: 4]
+    #[structopt(long, env = "ECHO_MAX_ATTEMPTS")]
+    pub max_attempts: Option<u32>,
+    /// Longest delay before retrying a call the first time, in milliseconds [default: 100]
+    #[structopt(long, env = "ECHO_INITIAL_BACKOFF_MS")]
+    pub initial_backoff_ms: Option<u64>,
+    /// Longest delay before retrying a call, in milliseconds [default: 2000]
+    #[structopt(long, env = "ECHO_MAX_BACKOFF_MS")]
+    pub max_backoff_ms: Option<u64>,
+    /// Maximum number of concurrent requests on the connection
+    #[structopt(long, env = "ECHO_CONCURRENCY_LIMIT")]
+    pub concurrency_limit: Option<usize>,
+    /// PEM CA certificate verifying the server, enables TLS
+    #[structopt(long, env = "ECHO_TLS_CA")]
+    pub tls_ca: Option<PathBuf>,
+    /// Name expected in the server certificate
+    #[structopt(long, env = "ECHO_TLS_DOMAIN")]
+    pub tls_domain: Option<String>,
+    /// PEM client certificate, for servers requiring mutual TLS
+    #[structopt(long, env = "ECHO_TLS_CLIENT_CERT")]
+    pub tls_client_cert: Option<PathBuf>,
+    /// PEM client key
+    #[structopt(long, env = "ECHO_TLS_CLIENT_KEY")]
+    pub tls_client_key: Option<PathBuf>,
+    /// Bearer token sent with every request
+    #[structopt(long, env = "ECHO_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    /// How calls are balanced over several servers: `round-robin` or `least-loaded` [default: round-robin]
+    #[structopt(long, env = "ECHO_BALANCING")]
+    pub balancing: Option<String>,
+    /// Interval between health checks of several servers, ejecting the ones not serving, in milliseconds [default: 5000]
+    #[structopt(long, env = "ECHO_HEALTH_CHECK_INTERVAL_MS")]
+    pub health_check_interval_ms: Option<u64>,
+    /// Compression of the requests: `gzip`, `zstd`, or `none`. Compressed responses are always accepted [default: none]
+    #[structopt(long, env = "ECHO_COMPRESSION")]
+    pub compression: Option<String>,
+}
+
+impl ClientOpt {
+    /// read the options of a config file.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// complete the missing options with the ones of another source.
+    pub fn or(self, other: ClientOpt) -> Self {
+        ClientOpt {
+            config: self.config.or(other.config),
+            target: self.target.or(other.target),
+            connect_timeout_ms: self.connect_timeout_ms.or(other.connect_timeout_ms),
+            timeout_ms: self.timeout_ms.or(other.timeout_ms),
+            deadline_ms: self.deadline_ms.or(other.deadline_ms),
+            max_attempts: self.max_attempts.or(other.max_attempts),
+            initial_backoff_ms: self.initial_backoff_ms.or(other.initial_backoff_ms),
+            max_backoff_ms: self.max_backoff_ms.or(other.max_backoff_ms),
+            concurrency_limit: self.concurrency_limit.or(other.concurrency_limit),
+            tls_ca: self.tls_ca.or(other.tls_ca),
+            tls_domain: self.tls_domain.or(other.tls_domain),
+            tls_client_cert: self.tls_client_cert.or(other.tls_client_cert),
+            tls_client_key: self.tls_client_key.or(other.tls_client_key),
+            token: self.token.or(other.token),
+            balancing: self.balancing.or(other.balancing),
+            health_check_interval_ms: self
+                .health_check_interval_ms
+                .or(other.health_check_interval_ms),
+            compression: self.compression.or(other.compression),
+        }
+    }
+}
+
+/// TLS settings of the client.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub ca_path: PathBuf,
+    pub domain: Option<String>,
+    pub identity_paths: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsConfig {
+    /// load the certificates and key.
+    pub fn load(&self) -> std::io::Result<ClientTlsConfig> {
+        let ca = std::fs::read(&self.ca_path)?;
+        let mut tls = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+        if let Some(domain) = &self.domain {
+            tls = tls.domain_name(domain.clone());
+        }
+        if let Some((cert_path, key_path)) = &self.identity_paths {
+            let cert = std::fs::read(cert_path)?;
+            let key = std::fs::read(key_path)?;
+            tls = tls.identity(Identity::from_pem(cert, key));
+        }
+        Ok(tls)
+    }
+}
+
+/// Interceptor attaching a bearer token to requests.
+#[derive(Debug, Clone)]
+pub struct Authorization {
+    header: Option<AsciiMetadataValue>,
+}
+
+impl Interceptor for Authorization {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(header) = &self.header {
+            request
+                .metadata_mut()
+                .insert("authorization", header.clone());
+        }
+        Ok(request)
+    }
+}
+
+/// Resolved client settings.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub target: String,
+    pub connect_timeout: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub deadline: Option<Duration>,
+    pub retry: RetryPolicy,
+    pub concurrency_limit: Option<usize>,
+    pub tls: Option<TlsConfig>,
+    pub token: Option<String>,
+    pub balancing: Balancing,
+    pub health_check: HealthCheck,
+    pub compression: Option<CompressionEncoding>,
+}
+
+const DEFAULT_TARGET: &str = "http://[::1]:50051";
+const DEFAULT_TLS_TARGET: &str = "https://[::1]:50051";
+const DEFAULT_HEALTH_CHECK_INTERVAL_MS: u64 = 5000;
+
+impl ClientConfig {
+    /// read the settings from the command line, the environment and the
+    /// config file.
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        ClientConfig::from_opt(ClientOpt::from_args())
+    }
+
+    /// resolve the settings, completing the options with the config file.
+    pub fn from_opt(opt: ClientOpt) -> Result<Self, Box<dyn std::error::Error>> {
+        let opt = match &opt.config {
+            Some(path) => {
+                let file = ClientOpt::from_file(path)?;
+                opt.or(file)
+            }
+            None => opt,
+        };
+
+        let identity_paths = match (opt.tls_client_cert, opt.tls_client_key) {
+            (Some(cert_path), Some(key_path)) => Some((cert_path, key_path)),
+            (None, None) => None,
+            _ => return Err("both a TLS client certificate and key are needed".into()),
+        };
+        let domain = opt.tls_domain;
+        let tls = opt.tls_ca.map(|ca_path| TlsConfig {
+            ca_path,
+            domain,
+            identity_paths,
+        });
+        let default_target = if tls.is_some() {
+            DEFAULT_TLS_TARGET
+        } else {
+            DEFAULT_TARGET
+        };
+
+        let default_retry = RetryPolicy::default();
+        let retry = RetryPolicy {
+            max_attempts: opt.max_attempts.unwrap_or(default_retry.max_attempts),
+            initial_backoff: opt
+                .initial_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default_retry.initial_backoff),
+            max_backoff: opt
+                .max_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default_retry.max_backoff),
+            ..default_retry
+        };
+        retry.validate()?;
+
+        Ok(ClientConfig {
+            target: opt.target.unwrap_or_else(|| default_target.into()),
+            connect_timeout: match opt.connect_timeout_ms {
+                Some(0) => return Err("the connect timeout must be positive".into()),
+                ms => ms.map(Duration::from_millis),
+            },
+            timeout: match opt.timeout_ms {
+                Some(0) => return Err("the timeout must be positive".into()),
+                ms => ms.map(Duration::from_millis),
+            },
+            deadline: match opt.deadline_ms {
+                Some(0) => return Err("the deadline must be positive".into()),
+                ms => ms.map(Duration::from_millis),
+            },
+            retry,
+            concurrency_limit: match opt.concurrency_limit {
+                Some(0) => return Err("the concurrency limit must be positive".into()),
+                limit => limit,
+            },
+            tls,
+            token: opt.token,
+            balancing: match &opt.balancing {
+                Some(balancing) => balancing.parse()?,
+                None => Balancing::default(),
+            },
+            health_check: HealthCheck {
+                interval: match opt.health_check_interval_ms {
+                    Some(0) => return Err("the health check interval must be positive".into()),
+                    Some(ms) => Duration::from_millis(ms),
+                    None => Duration::from_millis(DEFAULT_HEALTH_CHECK_INTERVAL_MS),
+                },
+            },
+            compression: match &opt.compression {
+                Some(name) => compression::parse(name)?,
+                None => None,
+            },
+        })
+    }
+
+    /// interceptor attaching the bearer token to requests, if any.
+    pub fn authorization(&self) -> Result<Authorization, Box<dyn std::error::Error>> {
+        let header = match &self.token {
+            Some(token) => Some(format!("Bearer {}", token).parse()?),
+            None => None,
+        };
+        Ok(Authorization { header })
+    }
+
+    /// connector to an endpoint: an url, or `unix:<path>` for a unix socket.
+    /// Channels connect on their first call, and reconnect whenever the
+    /// connection is lost, so the server doesn't have to be up yet.
+    fn connector(&self, tls_domain: Option<&str>) -> Result<Connect, Box<dyn std::error::Error>> {
+        let tls = match &self.tls {
+            Some(tls) => {
+                let mut config = tls.load()?;
+                // certificates name hosts, not the addresses they resolve to.
+                if let (None, Some(domain)) = (&tls.domain, tls_domain) {
+                    config = config.domain_name(domain);
+                }
+                Some(config)
+            }
+            None => None,
+        };
+        let connect_timeout = self.connect_timeout;
+        let timeout = self.timeout;
+        let concurrency_limit = self.concurrency_limit;
+
+        Ok(Arc::new(move |target: &str| {
+            let unix_path = target.strip_prefix("unix:").map(PathBuf::from);
+            // the uri is ignored when connecting to a unix socket, but still has
+            // to be valid.
+            let uri = match unix_path {
+                Some(_) => "http://[::]:50051".into(),
+                None => target.to_string(),
+            };
+
+            let mut endpoint = Endpoint::from_shared(uri)?;
+            if let Some(connect_timeout) = connect_timeout {
+                endpoint = endpoint.connect_timeout(connect_timeout);
+            }
+            if let Some(timeout) = timeout {
+                endpoint = endpoint.timeout(timeout);
+            }
+            if let Some(limit) = concurrency_limit {
+                endpoint = endpoint.concurrency_limit(limit);
+            }
+
+            match (unix_path, &tls) {
+                (Some(_), Some(_)) => Err("TLS is not supported over unix sockets".into()),
+                (Some(path), None) => Ok(endpoint.connect_with_connector_lazy(tower::service_fn(
+                    move |_| {
+                        let path = path.clone();
+                        async move {
+                            let stream = tokio::net::UnixStream::connect(path).await?;
+                            Ok::<_, std::io::Error>(TokioIo::new(stream))
+                        }
+                    },
+                ))),
+                (None, Some(tls)) => Ok(endpoint.tls_config(tls.clone())?.connect_lazy()),
+                (None, None) => Ok(endpoint.connect_lazy()),
+            }
+        }))
+    }
+
+    /// channel to the target, balancing the calls if it has several servers.
+    /// Those must be created within a Tokio runtime, which health checks them
+    /// in the background.
+    pub fn channel(&self) -> Result<BalancedChannel, Box<dyn std::error::Error>> {
+        let endpoints: Endpoints = self.target.parse()?;
+        if let (Some(_), Endpoints::Static(uris)) = (&self.tls, &endpoints) {
+            if uris.iter().any(|uri| uri.starts_with("unix:")) {
+                return Err("TLS is not supported over unix sockets".into());
+            }
+        }
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        let connect = match &endpoints {
+            Endpoints::Dns { host, .. } => self.connector(Some(host))?,
+            Endpoints::Static(_) => self.connector(None)?,
+        };
+        let channel = match endpoints {
+            Endpoints::Static(uris) if uris.len() == 1 => {
+                connect(&uris[0]).map(BalancedChannel::single)
+            }
+            endpoints => BalancedChannel::new(
+                endpoints,
+                scheme,
+                connect,
+                self.balancing,
+                self.health_check,
+            ),
+        };
+        channel.map_err(|err| err as Box<dyn std::error::Error>)
+    }
+
+    /// echo client to the target, authenticated, compressing and retrying as
+    /// configured.
+    pub fn client(&self) -> Result<RetryingClient, Box<dyn std::error::Error>> {
+        let mut client =
+            EchoServiceClient::with_interceptor(self.channel()?, self.authorization()?);
+        for encoding in compression::ENCODINGS.iter() {
+            client = client.accept_compressed(*encoding);
+        }
+        if let Some(encoding) = self.compression {
+            client = client.send_compressed(encoding);
+        }
+        Ok(RetryingClient::new(
+            client,
+            self.retry.clone(),
+            self.deadline,
+        ))
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // checks the defaults.
+    fn defaults() {
+        let config = ClientConfig::from_opt(ClientOpt::default()).unwrap();
+        assert_eq!("http://[::1]:50051", config.target);
+        assert_eq!(None, config.timeout);
+        assert!(config.tls.is_none());
+        assert_eq!(Balancing::RoundRobin, config.balancing);
+        assert_eq!(Duration::from_secs(5), config.health_check.interval);
+        assert_eq!(None, config.compression);
+
+        let opt = ClientOpt {
+            tls_ca: Some("ca.pem".into()),
+            ..Default::default()
+        };
+        let config = ClientConfig::from_opt(opt).unwrap();
+        assert_eq!("https://[::1]:50051", config.target);
+    }
+
+    #[test]
+    // checks the bearer token is attached to requests.
+    fn authorization() {
+        let config = ClientConfig::from_opt(ClientOpt::default()).unwrap();
+        let request = config
+            .authorization()
+            .unwrap()
+            .call(Request::new(()))
+            .unwrap();
+        assert!(request.metadata().get("authorization").is_none());
+
+        let opt = ClientOpt {
+            token: Some("secret".into()),
+            ..Default::default()
+        };
+        let config = ClientConfig::from_opt(opt).unwrap();
+        let request = config
+            .authorization()
+            .unwrap()
+            .call(Request::new(()))
+            .unwrap();
+        assert_eq!(
+            "Bearer secret",
+            request.metadata().get("authorization").unwrap()
+        );
+    }
+
+    #[test]
+    // checks the command line takes precedence over the config file.
+    fn command_line_over_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.toml");
+        std::fs::write(
+            &path,
+            "target = \"http://127.0.0.1:4000\"\ntimeout_ms = 500\nconnect_timeout_ms = 100\n",
+        )
+        .unwrap();
+
+        let opt = ClientOpt {
+            config: Some(path),
+            timeout_ms: Some(1000),
+            ..Default::default()
+        };
+        let config = ClientConfig::from_opt(opt).unwrap();
+        assert_eq!("http://127.0.0.1:4000", config.target);
+        assert_eq!(Some(Duration::from_millis(1000)), config.timeout);
+        assert_eq!(Some(Duration::from_millis(100)), config.connect_timeout);
+    }
+
+    #[test]
+    // checks zero timeouts and concurrency limits are refused.
+    fn invalid() {
+        for opt in [
+            ClientOpt {
+                timeout_ms: Some(0),
+                ..Default::default()
+            },
+            ClientOpt {
+                connect_timeout_ms: Some(0),
+                ..Default::default()
+            },
+            ClientOpt {
+                concurrency_limit: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(ClientConfig::from_opt(opt).is_err());
+        }
+    }
+
+    #[test]
+    // checks retry settings.
+    fn retry() {
+        let config = ClientConfig::from_opt(ClientOpt::default()).unwrap();
+        assert_eq!(RetryPolicy::default(), config.retry);
+        assert_eq!(None, config.deadline);
+
+        let opt = ClientOpt {
+            deadline_ms: Some(500),
+            max_attempts: Some(2),
+            initial_backoff_ms: Some(10),
+            ..Default::default()
+        };
+        let config = ClientConfig::from_opt(opt).unwrap();
+        assert_eq!(Some(Duration::from_millis(500)), config.deadline);
+        assert_eq!(2, config.retry.max_attempts);
+        assert_eq!(Duration::from_millis(10), config.retry.initial_backoff);
+        assert_eq!(Duration::from_secs(2), config.retry.max_backoff);
+
+        for opt in [
+            ClientOpt {
+                max_attempts: Some(0),
+                ..Default::default()
+            },
+            ClientOpt {
+                deadline_ms: Some(0),
+                ..Default::default()
+            },
+            ClientOpt {
+                initial_backoff_ms: Some(500),
+                max_backoff_ms: Some(100),
+                ..Default::default()
+            },
+        ] {
+            assert!(ClientConfig::from_opt(opt).is_err());
+        }
+    }
+
+    #[tokio::test]
+    // checks balancing settings.
+    async fn balancing() {
+        let opt = ClientOpt {
+            target: Some("http://127.0.0.1:4000,http://127.0.0.1:4001".into()),
+            balancing: Some("least-loaded".into()),
+            health_check_interval_ms: Some(100),
+            ..Default::default()
+        };
+        let config = ClientConfig::from_opt(opt).unwrap();
+        assert_eq!(Balancing::LeastLoaded, config.balancing);
+        assert_eq!(Duration::from_millis(100), config.health_check.interval);
+        assert!(config.channel().is_ok());
+
+        for opt in [
+            ClientOpt {
+                balancing: Some("random".into()),
+                ..Default::default()
+            },
+            ClientOpt {
+                health_check_interval_ms: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(ClientConfig::from_opt(opt).is_err());
+        }
+        let opt = ClientOpt {
+            target: Some("dns:localhost".into()),
+            ..Default::default()
+        };
+        assert!(ClientConfig::from_opt(opt).unwrap().channel().is_err());
+    }
+
+    #[tokio::test]
+    // checks TLS can't be asked over unix sockets.
+    async fn unix_socket_tls() {
+        let opt = ClientOpt {
+            target: Some("unix:/tmp/echo.sock".into()),
+            tls_ca: Some("ca.pem".into()),
+            ..Default::default()
+        };
+        let config = ClientConfig::from_opt(opt).unwrap();
+        // refused before loading the missing CA.
+        let err = config.channel().err().unwrap();
+        assert_eq!("TLS is not supported over unix sockets", err.to_string());
+    }
+}
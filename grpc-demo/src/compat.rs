@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+
+use prost::Message;
+use prost_types::field_descriptor_proto::Type;
+use prost_types::{DescriptorProto, EnumDescriptorProto, FileDescriptorSet};
+use serde::{Deserialize, Serialize};
+
+/// A field, as seen on the wire and in JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Field {
+    pub name: String,
+    /// scalar type, or name of the message or enum.
+    pub kind: String,
+    /// `optional`, `repeated` or `required`.
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oneof: Option<String>,
+}
+
+/// A message: its fields and reserved numbers, by number.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MessageSchema {
+    pub fields: BTreeMap<i32, Field>,
+    /// inclusive ranges of reserved field numbers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reserved: Vec<(i32, i32)>,
+}
+
+/// An enum: the names of its values, by number.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EnumSchema {
+    pub values: BTreeMap<i32, String>,
+    /// inclusive ranges of reserved value numbers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reserved: Vec<(i32, i32)>,
+}
+
+/// A method of a service.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Method {
+    pub input: String,
+    pub output: String,
+    pub client_streaming: bool,
+    pub server_streaming: bool,
+}
+
+/// What the clients of a proto package depend on. Names are relative to the
+/// package, so different versions of a package can be compared.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Schema {
+    pub messages: BTreeMap<String, MessageSchema>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub enums: BTreeMap<String, EnumSchema>,
+    /// the methods of each service, by name.
+    pub services: BTreeMap<String, BTreeMap<String, Method>>,
+}
+
+fn is_reserved(reserved: &[(i32, i32)], number: i32) -> bool {
+    reserved
+        .iter()
+        .any(|(start, end)| (*start..=*end).contains(&number))
+}
+
+/// name of a type referenced by a descriptor, relative to the package if it is
+/// one of its types.
+fn type_name(name: &str, package: &str) -> String {
+    let name = name.strip_prefix('.').unwrap_or(name);
+    match name
+        .strip_prefix(package)
+        .and_then(|name| name.strip_prefix('.'))
+    {
+        Some(relative) if !package.is_empty() => relative.to_string(),
+        _ => name.to_string(),
+    }
+}
+
+/// lowercase name of a descriptor enum value, without its prefix, e.g.
+/// `TYPE_STRING` as `string`.
+fn short_name(name: &str, prefix: &str) -> String {
+    name.trim_start_matches(prefix).to_lowercase()
+}
+
+impl Schema {
+    /// the schema of a package, out of an encoded `FileDescriptorSet`.
+    pub fn of_package(descriptor_set: &[u8], package: &str) -> Result<Self, prost::DecodeError> {
+        let descriptor_set = FileDescriptorSet::decode(descriptor_set)?;
+        let mut schema = Schema::default();
+        for file in descriptor_set
+            .file
+            .iter()
+            .filter(|file| file.package() == package)
+        {
+            for message in file.message_type.iter() {
+                schema.add_message(message, "", package);
+            }
+            for enumeration in file.enum_type.iter() {
+                schema.add_enum(enumeration, "");
+            }
+            for service in file.service.iter() {
+                let methods = service
+                    .method
+                    .iter()
+                    .map(|method| {
+                        let description = Method {
+                            input: type_name(method.input_type(), package),
+                            output: type_name(method.output_type(), package),
+                            client_streaming: method.client_streaming(),
+                            server_streaming: method.server_streaming(),
+                        };
+                        (method.name().to_string(), description)
+                    })
+                    .collect();
+                schema.services.insert(service.name().to_string(), methods);
+            }
+        }
+        Ok(schema)
+    }
+
+    /// add a message and its nested types, `scope` being the name of the
+    /// message they are nested in, if any.
+    fn add_message(&mut self, message: &DescriptorProto, scope: &str, package: &str) {
+        let name = format!("{}{}", scope, message.name());
+        let mut schema = MessageSchema {
+            reserved: message
+                .reserved_range
+                .iter()
+                // the end of message ranges is exclusive.
+                .map(|range| (range.start(), range.end() - 1))
+                .collect(),
+            ..Default::default()
+        };
+        for field in message.field.iter() {
+            let kind = match field.r#type() {
+                Type::Message | Type::Enum | Type::Group => type_name(field.type_name(), package),
+                scalar => short_name(scalar.as_str_name(), "TYPE_"),
+            };
+            // proto3 optional fields are in a synthetic oneof of their own,
+            // which changes nothing on the wire.
+            let oneof = field
+                .oneof_index
+                .filter(|_| !field.proto3_optional())
+                .and_then(|index| message.oneof_decl.get(index as usize))
+                .map(|oneof| oneof.name().to_string());
+            schema.fields.insert(
+                field.number(),
+                Field {
+                    name: field.name().to_string(),
+                    kind,
+                    label: short_name(field.label().as_str_name(), "LABEL_"),
+                    oneof,
+                },
+            );
+        }
+        let scope = format!("{}.", name);
+        for nested in message.nested_type.iter() {
+            // map entries are described by their map field.
+            if !nested
+                .options
+                .as_ref()
+                .is_some_and(|options| options.map_entry())
+            {
+                self.add_message(nested, &scope, package);
+            }
+        }
+        for enumeration in message.enum_type.iter() {
+            self.add_enum(enumeration, &scope);
+        }
+        self.messages.insert(name, schema);
+    }
+
+    fn add_enum(&mut self, enumeration: &EnumDescriptorProto, scope: &str) {
+        let schema = EnumSchema {
+            values: enumeration
+                .value
+                .iter()
+                .map(|value| (value.number(), value.name().to_string()))
+                .collect(),
+            reserved: enumeration
+                .reserved_range
+                .iter()
+                .map(|range| (range.start(), range.end()))
+                .collect(),
+        };
+        self.enums
+            .insert(format!("{}{}", scope, enumeration.name()), schema);
+    }
+}
+
+/// the changes breaking the clients of a baseline schema, on the wire or in
+/// JSON. Adding anything is fine, as is removing a field or an enum value
+/// whose number is then reserved.
+pub fn breaking_changes(baseline: &Schema, current: &Schema) -> Vec<String> {
+    let mut changes = Vec::new();
+    for (name, message) in baseline.messages.iter() {
+        let current = match current.messages.get(name) {
+            Some(current) => current,
+            None => {
+                changes.push(format!("message {} was removed", name));
+                continue;
+            }
+        };
+        for (number, field) in message.fields.iter() {
+            let field_name = format!("{}.{} ({})", name, field.name, number);
+            let new = match current.fields.get(number) {
+                Some(new) => new,
+                None if is_reserved(&current.reserved, *number) => continue,
+                None => {
+                    changes.push(format!(
+                        "field {} was removed without reserving its number",
+                        field_name
+                    ));
+                    continue;
+                }
+            };
+            if new.name != field.name {
+                changes.push(format!("field {} was renamed to {}", field_name, new.name));
+            }
+            if new.kind != field.kind {
+                changes.push(format!(
+                    "field {} changed type from {} to {}",
+                    field_name, field.kind, new.kind
+                ));
+            }
+            if new.label != field.label {
+                changes.push(format!(
+                    "field {} changed from {} to {}",
+                    field_name, field.label, new.label
+                ));
+            }
+            if new.oneof != field.oneof {
+                changes.push(format!("field {} changed of oneof", field_name));
+            }
+        }
+    }
+
+    for (name, enumeration) in baseline.enums.iter() {
+        let current = match current.enums.get(name) {
+            Some(current) => current,
+            None => {
+                changes.push(format!("enum {} was removed", name));
+                continue;
+            }
+        };
+        for (number, value) in enumeration.values.iter() {
+            match current.values.get(number) {
+                Some(new) if new != value => changes.push(format!(
+                    "value {}.{} ({}) was renamed to {}",
+                    name, value, number, new
+                )),
+                None if !is_reserved(&current.reserved, *number) => changes.push(format!(
+                    "value {}.{} ({}) was removed without reserving its number",
+                    name, value, number
+                )),
+                _ => {}
+            }
+        }
+    }
+
+    for (name, methods) in baseline.services.iter() {
+        let current = match current.services.get(name) {
+            Some(current) => current,
+            None => {
+                changes.push(format!("service {} was removed", name));
+                continue;
+            }
+        };
+        for (method_name, method) in methods.iter() {
+            match current.get(method_name) {
+                Some(new) if new != method => {
+                    changes.push(format!("method {}.{} changed signature", name, method_name))
+                }
+                None => changes.push(format!("method {}.{} was removed", name, method_name)),
+                _ => {}
+            }
+        }
+    }
+    changes
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echo::FILE_DESCRIPTOR_SET;
+    use std::path::PathBuf;
+
+    // packages checked against their committed baseline.
+    const PACKAGES: [&str; 2] = ["echo.v1", "echo"];
+
+    fn baseline_path(package: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("proto/baseline")
+            .join(format!("{}.json", package))
+    }
+
+    #[test]
+    // checks the served packages did not break since their baselines, and
+    // that the baselines are up to date. After a compatible change, update
+    // them with `UPDATE_PROTO_BASELINE=1 cargo test baselines`.
+    fn baselines() {
+        let update = std::env::var_os("UPDATE_PROTO_BASELINE").is_some();
+        for package in PACKAGES.iter() {
+            let current = Schema::of_package(FILE_DESCRIPTOR_SET, package).unwrap();
+            let path = baseline_path(package);
+            let baseline: Schema = match std::fs::read_to_string(&path) {
+                Ok(json) => serde_json::from_str(&json).unwrap(),
+                Err(_) if update => Schema::default(),
+                Err(err) => panic!("cannot read {}: {}", path.display(), err),
+            };
+
+            let changes = breaking_changes(&baseline, &current);
+            assert!(
+                changes.is_empty(),
+                "breaking changes in {}, make a new version of the package instead:\n  {}",
+                package,
+                changes.join("\n  ")
+            );
+            if update {
+                let json = serde_json::to_string_pretty(&current).unwrap();
+                std::fs::write(&path, json + "\n").unwrap();
+            } else {
+                assert_eq!(
+                    baseline, current,
+                    "{} changed, update its baseline with UPDATE_PROTO_BASELINE=1",
+                    package
+                );
+            }
+        }
+    }
+
+    #[test]
+    // checks v1 answers the clients of the legacy package, which are served
+    // by the v1 service.
+    fn legacy_compatible() {
+        let legacy = Schema::of_package(FILE_DESCRIPTOR_SET, "echo").unwrap();
+        let v1 = Schema::of_package(FILE_DESCRIPTOR_SET, "echo.v1").unwrap();
+        assert!(!legacy.services.is_empty());
+        assert_eq!(Vec::<String>::new(), breaking_changes(&legacy, &v1));
+    }
+
+    fn field(name: &str, kind: &str) -> Field {
+        Field {
+            name: name.into(),
+            kind: kind.into(),
+            label: "optional".into(),
+            oneof: None,
+        }
+    }
+
+    fn schema(fields: Vec<(i32, Field)>, reserved: Vec<(i32, i32)>) -> Schema {
+        let mut schema = Schema::default();
+        schema.messages.insert(
+            "SayRequest".into(),
+            MessageSchema {
+                fields: fields.into_iter().collect(),
+                reserved,
+            },
+        );
+        schema
+    }
+
+    #[test]
+    // checks breaking field changes are flagged, and compatible ones are not.
+    fn field_changes() {
+        let baseline = schema(
+            vec![
+                (1, field("message", "string")),
+                (2, field("count", "uint32")),
+            ],
+            vec![],
+        );
+        let breaking = |fields, reserved| breaking_changes(&baseline, &schema(fields, reserved));
+
+        // adding a field, or removing one and reserving its number.
+        assert!(breaking(
+            vec![
+                (1, field("message", "string")),
+                (2, field("count", "uint32")),
+                (3, field("locale", "string")),
+            ],
+            vec![],
+        )
+        .is_empty());
+        assert!(breaking(vec![(1, field("message", "string"))], vec![(2, 2)]).is_empty());
+
+        assert_eq!(
+            vec!["field SayRequest.count (2) was removed without reserving its number"],
+            breaking(vec![(1, field("message", "string"))], vec![])
+        );
+        assert_eq!(
+            vec![
+                "field SayRequest.message (1) was renamed to text",
+                "field SayRequest.count (2) changed type from uint32 to int64",
+            ],
+            breaking(
+                vec![(1, field("text", "string")), (2, field("count", "int64"))],
+                vec![],
+            )
+        );
+        assert_eq!(
+            vec!["message SayRequest was removed"],
+            breaking_changes(&baseline, &Schema::default())
+        );
+    }
+}
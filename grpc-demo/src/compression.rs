@@ -0,0 +1,62 @@
+use tonic::codec::CompressionEncoding;
+
+/// Every supported message compression, by order of preference.
+pub const ENCODINGS: [CompressionEncoding; 2] =
+    [CompressionEncoding::Zstd, CompressionEncoding::Gzip];
+
+/// parse a compression name: `gzip`, `zstd`, or `none` for no compression.
+pub fn parse(name: &str) -> Result<Option<CompressionEncoding>, String> {
+    match name {
+        "gzip" => Ok(Some(CompressionEncoding::Gzip)),
+        "zstd" => Ok(Some(CompressionEncoding::Zstd)),
+        "none" => Ok(None),
+        _ => Err(format!(
+            "unknown compression {:?}, expected gzip, zstd or none",
+            name
+        )),
+    }
+}
+
+/// parse a list of compression names, where `none` alone means no
+/// compression at all.
+pub fn parse_list(names: &[String]) -> Result<Vec<CompressionEncoding>, String> {
+    let mut encodings = Vec::new();
+    for name in names {
+        match parse(name)? {
+            Some(encoding) if !encodings.contains(&encoding) => encodings.push(encoding),
+            Some(_) => {}
+            None if names.len() == 1 => {}
+            None => return Err("`none` cannot be mixed with other compressions".into()),
+        }
+    }
+    Ok(encodings)
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // checks compression names parsing.
+    fn names() {
+        assert_eq!(Ok(Some(CompressionEncoding::Gzip)), parse("gzip"));
+        assert_eq!(Ok(Some(CompressionEncoding::Zstd)), parse("zstd"));
+        assert_eq!(Ok(None), parse("none"));
+        assert!(parse("brotli").is_err());
+
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            Ok(vec![CompressionEncoding::Zstd, CompressionEncoding::Gzip]),
+            parse_list(&names(&["zstd", "gzip", "zstd"]))
+        );
+        assert_eq!(Ok(vec![]), parse_list(&names(&["none"])));
+        assert!(parse_list(&names(&["none", "gzip"])).is_err());
+    }
+}
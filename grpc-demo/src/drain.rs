@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use http_body::{Body, Frame, SizeHint};
+use tokio::sync::{oneshot, watch};
+use tonic::Status;
+use tower::{Layer, Service};
+
+/// RPCs currently running on the server, so the ones still running when the
+/// drain deadline expires can be cancelled and reported.
+#[derive(Debug, Clone)]
+pub struct InFlight {
+    rpcs: Arc<Mutex<Rpcs>>,
+    // set once every RPC must be cancelled.
+    cancel: Arc<watch::Sender<bool>>,
+}
+
+#[derive(Debug, Default)]
+struct Rpcs {
+    next_id: u64,
+    // method and start time of each running RPC.
+    running: HashMap<u64, (String, Instant)>,
+}
+
+/// An RPC still running when the drain deadline expired.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CancelledRpc {
+    pub method: String,
+    pub running_for: Duration,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        InFlight {
+            rpcs: Default::default(),
+            cancel: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// record an RPC as running, until the returned guard is dropped.
+    fn start(&self, method: String) -> RpcGuard {
+        let mut rpcs = self.rpcs.lock().unwrap();
+        let id = rpcs.next_id;
+        rpcs.next_id += 1;
+        rpcs.running.insert(id, (method, Instant::now()));
+        RpcGuard {
+            in_flight: self.clone(),
+            id,
+        }
+    }
+
+    /// return the running RPCs, oldest first.
+    pub fn running(&self) -> Vec<CancelledRpc> {
+        let rpcs = self.rpcs.lock().unwrap();
+        let mut running = rpcs.running.iter().collect::<Vec<_>>();
+        running.sort_by_key(|(id, _)| **id);
+        running
+            .into_iter()
+            .map(|(_, (method, started_at))| CancelledRpc {
+                method: method.clone(),
+                running_for: started_at.elapsed(),
+            })
+            .collect()
+    }
+
+    /// cancel every running RPC, and return them.
+    pub fn cancel(&self) -> Vec<CancelledRpc> {
+        let running = self.running();
+        self.cancel.send_replace(true);
+        running
+    }
+
+    /// resolve once the RPCs are cancelled.
+    fn cancelled(&self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let mut cancel = self.cancel.subscribe();
+        Box::pin(async move {
+            // the sender lives as long as this InFlight, so it is never dropped.
+            let _ = cancel.wait_for(|cancelled| *cancelled).await;
+        })
+    }
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// status of the cancelled RPCs.
+fn cancelled_status() -> Status {
+    Status::cancelled("server shutting down")
+}
+
+/// Removes its RPC from the running ones when dropped.
+#[derive(Debug)]
+struct RpcGuard {
+    in_flight: InFlight,
+    id: u64,
+}
+
+impl Drop for RpcGuard {
+    fn drop(&mut self) {
+        self.in_flight.rpcs.lock().unwrap().running.remove(&self.id);
+    }
+}
+
+/// Layer recording every RPC in an `InFlight`, from its request until the end
+/// of its response body, so streaming RPCs are counted until their last
+/// message. Cancelled RPCs end right away with a `CANCELLED` status.
+#[derive(Debug, Clone)]
+pub struct InFlightLayer {
+    in_flight: InFlight,
+}
+
+impl InFlightLayer {
+    pub fn new(in_flight: InFlight) -> Self {
+        InFlightLayer { in_flight }
+    }
+}
+
+impl<S> Layer<S> for InFlightLayer {
+    type Service = InFlightService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        InFlightService {
+            inner,
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InFlightService<S> {
+    inner: S,
+    in_flight: InFlight,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for InFlightService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = http::Response<TrackedBody<ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let guard = self.in_flight.start(request.uri().path().to_string());
+        let cancelled = self.in_flight.cancelled();
+        let body_cancelled = self.in_flight.cancelled();
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = tokio::select! {
+                response = response => response?,
+                _ = cancelled => cancelled_status().into_http(),
+            };
+            Ok(response.map(|body| TrackedBody {
+                body,
+                _guard: guard,
+                cancelled: body_cancelled,
+                done: false,
+            }))
+        })
+    }
+}
+
+/// Response body keeping its RPC running until it is fully sent, or dropped.
+/// Once cancelled, it ends with the cancelled status trailers.
+pub struct TrackedBody<B> {
+    body: B,
+    _guard: RpcGuard,
+    cancelled: Pin<Box<dyn Future<Output = ()> + Send>>,
+    // the cancelled status was sent.
+    done: bool,
+}
+
+impl<B: Body + Unpin> Body for TrackedBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        if self.cancelled.as_mut().poll(cx).is_ready() {
+            self.done = true;
+            let mut trailers = http::HeaderMap::new();
+            // the status is a fixed one, which always converts.
+            let _ = cancelled_status().add_header(&mut trailers);
+            return Poll::Ready(Some(Ok(Frame::trailers(trailers))));
+        }
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// wait for SIGINT or SIGTERM.
+pub async fn terminate_signal() {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("cannot listen to SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+/// run a server until it stops by itself or, once `draining` fires, until the
+/// deadline expires. In that case the RPCs still running are cancelled, and
+/// returned once the server stopped.
+pub async fn serve_with_deadline<E>(
+    serve: impl Future<Output = Result<(), E>>,
+    draining: oneshot::Receiver<()>,
+    deadline: Duration,
+    in_flight: &InFlight,
+) -> Result<Vec<CancelledRpc>, E> {
+    tokio::pin!(serve);
+    tokio::select! {
+        result = &mut serve => return result.map(|_| Vec::new()),
+        // the sender is dropped without sending if the server stops first.
+        Ok(()) = draining => {}
+    }
+    match tokio::time::timeout(deadline, &mut serve).await {
+        Ok(result) => result.map(|_| Vec::new()),
+        Err(_) => {
+            let cancelled = in_flight.cancel();
+            serve.await?;
+            Ok(cancelled)
+        }
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // checks RPCs are running until their guard is dropped.
+    fn in_flight() {
+        let in_flight = InFlight::new();
+        assert!(in_flight.running().is_empty());
+
+        let first = in_flight.start("/echo.v1.EchoService/Say".into());
+        let second = in_flight.start("/echo.v1.EchoService/Chat".into());
+        let methods = |in_flight: &InFlight| {
+            in_flight
+                .running()
+                .into_iter()
+                .map(|rpc| rpc.method)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["/echo.v1.EchoService/Say", "/echo.v1.EchoService/Chat"],
+            methods(&in_flight)
+        );
+        drop(first);
+        assert_eq!(vec!["/echo.v1.EchoService/Chat"], methods(&in_flight));
+        drop(second);
+        assert!(in_flight.running().is_empty());
+    }
+
+    #[tokio::test]
+    // checks the server is cancelled once the deadline expires.
+    async fn deadline() {
+        let in_flight = InFlight::new();
+        let _rpc = in_flight.start("/echo.v1.EchoService/Chat".into());
+
+        // a server stopping by itself reports nothing.
+        let (_draining, draining_rx) = oneshot::channel();
+        let serve = async { Ok::<(), ()>(()) };
+        let cancelled = serve_with_deadline(serve, draining_rx, Duration::from_secs(1), &in_flight)
+            .await
+            .unwrap();
+        assert!(cancelled.is_empty());
+
+        // a server waiting for its RPCs is stopped by cancelling them.
+        let (draining, draining_rx) = oneshot::channel();
+        draining.send(()).unwrap();
+        let serve = {
+            let in_flight = in_flight.clone();
+            async move {
+                in_flight.cancelled().await;
+                Ok::<(), ()>(())
+            }
+        };
+        let cancelled =
+            serve_with_deadline(serve, draining_rx, Duration::from_millis(10), &in_flight)
+                .await
+                .unwrap();
+        assert_eq!(1, cancelled.len());
+        assert_eq!("/echo.v1.EchoService/Chat", cancelled[0].method);
+    }
+}
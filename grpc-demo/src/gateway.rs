@@ -0,0 +1,333 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use http_body_util::{BodyExt, Full, Limited};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use tonic::{Code, Status};
+use tower::{Layer, Service};
+
+use crate::echo::v1::{SayRequest, SayResponse};
+
+// largest JSON request accepted, the default gRPC message limit.
+const MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+
+// path of the gateway endpoint, and of the RPC it calls.
+const SAY_PATH: &str = "/v1/say";
+const SAY_METHOD: &str = "/echo.v1.EchoService/Say";
+
+/// JSON body of the say requests and responses.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Say {
+    // missing, like any proto3 field, means empty.
+    #[serde(default)]
+    message: String,
+}
+
+/// JSON body of the errors.
+#[derive(Debug, Deserialize, Serialize)]
+struct Error {
+    code: i32,
+    message: String,
+}
+
+/// HTTP status of a gRPC status code, as mapped by the Google APIs.
+pub fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        // client closed request, a nginx extension.
+        Code::Cancelled => StatusCode::from_u16(499).unwrap(),
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// gRPC message frame of a protobuf message, uncompressed.
+fn encode_frame(message: &impl Message) -> Bytes {
+    let len = message.encoded_len();
+    let mut frame = BytesMut::with_capacity(5 + len);
+    frame.put_u8(0);
+    frame.put_u32(len as u32);
+    // the buffer was sized for the message, so it always fits.
+    message.encode(&mut frame).unwrap();
+    frame.freeze()
+}
+
+/// message of the single gRPC frame of a unary response.
+fn decode_frame<M: Message + Default>(mut frame: Bytes) -> Result<M, Status> {
+    if frame.len() < 5 {
+        return Err(Status::internal("missing response message"));
+    }
+    if frame.get_u8() != 0 {
+        return Err(Status::internal("compressed response message"));
+    }
+    let len = frame.get_u32() as usize;
+    if frame.len() != len {
+        return Err(Status::internal("truncated response message"));
+    }
+    M::decode(frame).map_err(|err| Status::internal(err.to_string()))
+}
+
+/// JSON response, carrying its gRPC status in the headers like a trailers-only
+/// response, so it is logged as such.
+fn json_response(
+    status: &Status,
+    body: &impl Serialize,
+    mut headers: HeaderMap,
+) -> http::Response<tonic::body::Body> {
+    let _ = status.add_header(&mut headers);
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    // plain structs of strings and numbers always serialize.
+    let body = serde_json::to_vec(body).unwrap();
+    let mut response = http::Response::new(tonic::body::Body::new(Full::new(Bytes::from(body))));
+    *response.status_mut() = http_status(status.code());
+    *response.headers_mut() = headers;
+    response
+}
+
+fn error_response(status: &Status, headers: HeaderMap) -> http::Response<tonic::body::Body> {
+    let error = Error {
+        code: status.code() as i32,
+        message: status.message().to_string(),
+    };
+    json_response(status, &error, headers)
+}
+
+/// Layer serving `POST /v1/say {"message": ..}` by transcoding it into a call
+/// of `EchoService::say`, so it goes through the same authentication, limits
+/// and logs as the gRPC calls. Errors are answered with the HTTP status of
+/// their gRPC code, and a JSON body holding the code and the message. Every
+/// other request goes through untouched.
+#[derive(Debug, Clone, Default)]
+pub struct GatewayLayer;
+
+impl<S> Layer<S> for GatewayLayer {
+    type Service = GatewayService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GatewayService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GatewayService<S> {
+    inner: S,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<S, ResBody> Service<http::Request<tonic::body::Body>> for GatewayService<S>
+where
+    S: Service<http::Request<tonic::body::Body>, Response = http::Response<ResBody>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+    ResBody: http_body::Body<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<tonic::body::Body>) -> Self::Future {
+        if request.uri().path() != SAY_PATH {
+            let response = self.inner.call(request);
+            return Box::pin(async move { Ok(response.await?.map(tonic::body::Body::new)) });
+        }
+        if request.method() != Method::POST {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ALLOW, HeaderValue::from_static("POST"));
+            let status = Status::unimplemented("only POST is allowed");
+            let mut response = error_response(&status, headers);
+            *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+            return Box::pin(async move { Ok(response) });
+        }
+
+        // the ready service is the one to call, and a fresh clone takes its place.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let request = match to_grpc(request).await {
+                Ok(request) => request,
+                Err(status) => return Ok(error_response(&status, HeaderMap::new())),
+            };
+            let response = inner.call(request).await?;
+            Ok(from_grpc(response).await)
+        })
+    }
+}
+
+/// gRPC say request of a JSON one, with the same metadata and extensions.
+async fn to_grpc(
+    request: http::Request<tonic::body::Body>,
+) -> Result<http::Request<tonic::body::Body>, Status> {
+    let (mut parts, body) = request.into_parts();
+    let body = Limited::new(body, MAX_REQUEST_SIZE)
+        .collect()
+        .await
+        .map_err(|err| Status::invalid_argument(format!("cannot read request: {}", err)))?
+        .to_bytes();
+    let say: Say = serde_json::from_slice(&body)
+        .map_err(|err| Status::invalid_argument(format!("invalid JSON request: {}", err)))?;
+    let frame = encode_frame(&SayRequest {
+        message: say.message,
+    });
+
+    // gRPC is carried by HTTP/2, whatever the version of the JSON request.
+    parts.version = http::Version::HTTP_2;
+    parts.method = Method::POST;
+    parts.uri = SAY_METHOD.parse().unwrap();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    // the frame sent is not compressed, and the gateway can only decode an
+    // uncompressed response, whatever the JSON client asked.
+    parts.headers.remove("grpc-encoding");
+    parts.headers.remove("grpc-accept-encoding");
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    parts
+        .headers
+        .insert(header::TE, HeaderValue::from_static("trailers"));
+    Ok(http::Request::from_parts(
+        parts,
+        tonic::body::Body::new(Full::new(frame)),
+    ))
+}
+
+/// JSON response of a gRPC say response.
+async fn from_grpc<B>(response: http::Response<B>) -> http::Response<tonic::body::Body>
+where
+    B: http_body::Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let (parts, body) = response.into_parts();
+    // metadata, such as the trace context, is passed along.
+    let mut headers = parts.headers.clone();
+    headers.remove(header::CONTENT_TYPE);
+    let grpc_headers = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with("grpc-"))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in grpc_headers {
+        headers.remove(name);
+    }
+
+    // responses without any message carry their status in the headers.
+    if let Some(status) = Status::from_header_map(&parts.headers) {
+        if status.code() != Code::Ok {
+            return error_response(&status, headers);
+        }
+    }
+    let body = match body.collect().await {
+        Ok(body) => body,
+        Err(err) => {
+            let status = Status::unavailable(format!("cannot read response: {}", err.into()));
+            return error_response(&status, headers);
+        }
+    };
+    let status = body
+        .trailers()
+        .and_then(Status::from_header_map)
+        .unwrap_or_else(|| Status::internal("missing response status"));
+    if status.code() != Code::Ok {
+        return error_response(&status, headers);
+    }
+    match decode_frame::<SayResponse>(body.to_bytes()) {
+        Ok(say) => json_response(
+            &status,
+            &Say {
+                message: say.message,
+            },
+            headers,
+        ),
+        Err(status) => error_response(&status, headers),
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // checks gRPC codes are mapped to HTTP statuses.
+    fn status_mapping() {
+        assert_eq!(StatusCode::OK, http_status(Code::Ok));
+        assert_eq!(StatusCode::BAD_REQUEST, http_status(Code::InvalidArgument));
+        assert_eq!(StatusCode::UNAUTHORIZED, http_status(Code::Unauthenticated));
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            http_status(Code::ResourceExhausted)
+        );
+        assert_eq!(499, http_status(Code::Cancelled).as_u16());
+    }
+
+    #[test]
+    // checks messages go through gRPC frames unchanged.
+    fn frames() {
+        let frame = encode_frame(&SayResponse {
+            message: "Hello Tonic!".into(),
+        });
+        assert_eq!(0, frame[0]);
+        assert_eq!(
+            frame.len() - 5,
+            u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]) as usize
+        );
+        let say: SayResponse = decode_frame(frame.clone()).unwrap();
+        assert_eq!("Hello Tonic!", say.message);
+
+        assert!(decode_frame::<SayResponse>(frame.slice(..frame.len() - 1)).is_err());
+        assert!(decode_frame::<SayResponse>(Bytes::new()).is_err());
+    }
+
+    #[tokio::test]
+    // checks JSON requests are turned into uncompressed gRPC ones, keeping
+    // their metadata.
+    async fn grpc_request() {
+        let request = http::Request::builder()
+            .method(Method::POST)
+            .uri(SAY_PATH)
+            .header("x-tenant", "acme")
+            .header("grpc-accept-encoding", "gzip,zstd")
+            .header("grpc-encoding", "gzip")
+            .body(tonic::body::Body::new(Full::new(Bytes::from(
+                r#"{"message":"Tonic"}"#,
+            ))))
+            .unwrap();
+        let request = to_grpc(request).await.unwrap();
+        assert_eq!(SAY_METHOD, request.uri().path());
+        let headers = request.headers();
+        assert_eq!("application/grpc", headers[header::CONTENT_TYPE]);
+        assert_eq!("acme", headers["x-tenant"]);
+        assert!(!headers.contains_key("grpc-accept-encoding"));
+        assert!(!headers.contains_key("grpc-encoding"));
+
+        let body = request.into_body().collect().await.unwrap().to_bytes();
+        let say: SayRequest = decode_frame(body).unwrap();
+        assert_eq!("Tonic", say.message);
+    }
+}
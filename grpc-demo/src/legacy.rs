@@ -0,0 +1,73 @@
+use std::task::{Context, Poll};
+
+use tonic::server::NamedService;
+use tower::Service;
+
+use crate::echo::echo_service_server::SERVICE_NAME as LEGACY_SERVICE_NAME;
+use crate::echo::v1::echo_service_server::SERVICE_NAME;
+
+/// Service answering the calls of the unversioned `echo.EchoService` with an
+/// `echo.v1.EchoService` one, by rewriting their paths. Their messages are the
+/// same on the wire, so the clients not migrated to v1 yet keep working.
+#[derive(Debug, Clone)]
+pub struct Legacy<S> {
+    inner: S,
+}
+
+impl<S> Legacy<S> {
+    pub fn new(inner: S) -> Self {
+        Legacy { inner }
+    }
+}
+
+impl<S> NamedService for Legacy<S> {
+    const NAME: &'static str = LEGACY_SERVICE_NAME;
+}
+
+/// the v1 path of a legacy method path, if it is one.
+fn v1_path(path: &str) -> Option<String> {
+    let method = path
+        .strip_prefix('/')?
+        .strip_prefix(LEGACY_SERVICE_NAME)?
+        .strip_prefix('/')?;
+    Some(format!("/{}/{}", SERVICE_NAME, method))
+}
+
+impl<S, B> Service<http::Request<B>> for Legacy<S>
+where
+    S: Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        // the router only sends the paths of the legacy service.
+        if let Some(uri) = v1_path(request.uri().path()).and_then(|path| path.parse().ok()) {
+            *request.uri_mut() = uri;
+        }
+        self.inner.call(request)
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // checks legacy paths are mapped to v1.
+    fn paths() {
+        assert_eq!(
+            Some("/echo.v1.EchoService/Say".to_string()),
+            v1_path("/echo.EchoService/Say")
+        );
+        assert_eq!(None, v1_path("/echo.EchoServiceX/Say"));
+        assert_eq!(None, v1_path("/echo.v1.EchoService/Say"));
+    }
+}
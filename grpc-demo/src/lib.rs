@@ -0,0 +1,33 @@
+//! Echo gRPC service, with the configuration, middlewares and client helpers
+//! shared by the server, client and load test binaries. The service can also
+//! be embedded in another server, or run in-process by tests.
+
+pub mod auth;
+pub mod balance;
+pub mod client_config;
+pub mod compat;
+pub mod compression;
+pub mod drain;
+pub mod gateway;
+pub mod legacy;
+pub mod limit;
+pub mod metrics;
+pub mod mock;
+pub mod propagation;
+pub mod retry;
+pub mod server_config;
+pub mod service;
+pub mod telemetry;
+pub mod validate;
+
+pub mod echo {
+    /// The current version of the API.
+    pub mod v1 {
+        tonic::include_proto!("echo.v1");
+    }
+
+    // the unversioned package, served during the migration to v1.
+    tonic::include_proto!("echo");
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("echo_descriptor");
+}
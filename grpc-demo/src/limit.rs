@@ -0,0 +1,439 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use http_body::{Body, Frame, SizeHint};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic::Status;
+use tower::{Layer, Service};
+
+use crate::telemetry::remote_addr;
+
+// number of peers remembered, before forgetting the least recently seen one.
+const MAX_PEERS: usize = 10_000;
+
+// services never limited: load balancers must see the health of a loaded
+// server as it is, and debugging tools must keep working.
+const UNLIMITED_SERVICES: [&str; 3] = [
+    tonic_health::pb::health_server::SERVICE_NAME,
+    tonic_reflection::pb::v1::server_reflection_server::SERVICE_NAME,
+    tonic_reflection::pb::v1alpha::server_reflection_server::SERVICE_NAME,
+];
+
+/// A number of requests per second, allowing bursts of up to `burst`
+/// requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// Token bucket: a request takes a token, and tokens are refilled at the
+/// rate of the limit, up to the burst.
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        Bucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * limit.per_second).min(limit.burst as f64);
+        self.updated_at = now;
+    }
+
+    /// whether there is a token left.
+    fn has_token(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        self.tokens >= 1.0
+    }
+
+    /// take a token, if there is one left.
+    fn try_take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        if !self.has_token(limit, now) {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Rate limit shared by every request.
+#[derive(Debug)]
+struct GlobalLimiter {
+    limit: RateLimit,
+    bucket: Mutex<Bucket>,
+}
+
+/// Rate limit of each client IP address. At most `MAX_PEERS` peers are
+/// remembered, the least recently seen one being forgotten for a new one.
+#[derive(Debug)]
+struct PeerLimiter {
+    limit: RateLimit,
+    peers: Mutex<Peers>,
+}
+
+#[derive(Debug, Default)]
+struct Peers {
+    // bucket of each peer, along with its last use.
+    buckets: HashMap<IpAddr, (Bucket, u64)>,
+    // peers by last use, least recently seen first.
+    by_use: BTreeMap<u64, IpAddr>,
+    next_use: u64,
+}
+
+impl PeerLimiter {
+    fn new(limit: RateLimit) -> Self {
+        PeerLimiter {
+            limit,
+            peers: Default::default(),
+        }
+    }
+
+    /// take a token of the peer, if it has one left and `also` lets the
+    /// request through too: a rejected request never spends a token.
+    fn try_take(
+        &self,
+        peer: IpAddr,
+        now: Instant,
+        also: impl FnOnce() -> Result<(), Status>,
+    ) -> Result<(), Status> {
+        let mut guard = self.peers.lock().unwrap();
+        let peers = &mut *guard;
+        let last_use = peers.next_use;
+        peers.next_use += 1;
+        let bucket = match peers.buckets.get_mut(&peer) {
+            Some((bucket, used)) => {
+                peers.by_use.remove(used);
+                *used = last_use;
+                bucket
+            }
+            None => {
+                if peers.buckets.len() >= MAX_PEERS {
+                    if let Some((_, oldest)) = peers.by_use.pop_first() {
+                        peers.buckets.remove(&oldest);
+                    }
+                }
+                let bucket = Bucket::full(&self.limit, now);
+                &mut peers.buckets.entry(peer).or_insert((bucket, last_use)).0
+            }
+        };
+        peers.by_use.insert(last_use, peer);
+
+        if !bucket.has_token(&self.limit, now) {
+            return Err(Status::resource_exhausted(
+                "too many requests from this peer",
+            ));
+        }
+        also()?;
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// whether the requests to a path are never limited.
+fn is_unlimited(path: &str) -> bool {
+    match path.strip_prefix('/').and_then(|path| path.split_once('/')) {
+        Some((service, _)) => UNLIMITED_SERVICES.contains(&service),
+        None => false,
+    }
+}
+
+/// Layer rejecting requests over the rate limits or the concurrency limit
+/// with `Status::resource_exhausted`. A request counts as running until the
+/// end of its response, so streaming RPCs are counted until their last
+/// message. Requests over unix sockets have no peer rate limit, and the
+/// health and reflection services are not limited at all.
+#[derive(Debug, Clone, Default)]
+pub struct LimitLayer {
+    global: Option<Arc<GlobalLimiter>>,
+    per_peer: Option<Arc<PeerLimiter>>,
+    concurrency: Option<Arc<Semaphore>>,
+}
+
+impl LimitLayer {
+    pub fn new() -> Self {
+        LimitLayer {
+            ..Default::default()
+        }
+    }
+
+    /// limit the rate of all the requests.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.global = Some(Arc::new(GlobalLimiter {
+            limit,
+            bucket: Mutex::new(Bucket::full(&limit, Instant::now())),
+        }));
+        self
+    }
+
+    /// limit the rate of the requests of each client IP address.
+    pub fn peer_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.per_peer = Some(Arc::new(PeerLimiter::new(limit)));
+        self
+    }
+
+    /// limit the number of requests running at the same time.
+    pub fn max_concurrent_requests(mut self, max: usize) -> Self {
+        self.concurrency = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// let a request through, or tell why it is rejected. The returned permit
+    /// must be kept while the request runs.
+    fn admit<B>(&self, request: &http::Request<B>) -> Result<Option<OwnedSemaphorePermit>, Status> {
+        let permit = match &self.concurrency {
+            Some(concurrency) => match concurrency.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => return Err(Status::resource_exhausted("too many concurrent requests")),
+            },
+            None => None,
+        };
+        let now = Instant::now();
+        let global = || match &self.global {
+            Some(global) if !global.bucket.lock().unwrap().try_take(&global.limit, now) => {
+                Err(Status::resource_exhausted("too many requests"))
+            }
+            _ => Ok(()),
+        };
+        match (&self.per_peer, remote_addr(request)) {
+            (Some(per_peer), Some(addr)) => per_peer.try_take(addr.ip(), now, global)?,
+            _ => global()?,
+        }
+        Ok(permit)
+    }
+}
+
+impl<S> Layer<S> for LimitLayer {
+    type Service = LimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LimitService {
+            inner,
+            limits: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitService<S> {
+    inner: S,
+    limits: LimitLayer,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for LimitService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = http::Response<PermitBody<ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let admitted = if is_unlimited(request.uri().path()) {
+            Ok(None)
+        } else {
+            self.limits.admit(&request)
+        };
+        let permit = match admitted {
+            Ok(permit) => permit,
+            Err(status) => {
+                let response = status.into_http();
+                return Box::pin(async move { Ok(response) });
+            }
+        };
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await?;
+            Ok(response.map(|body| PermitBody {
+                body,
+                _permit: permit,
+            }))
+        })
+    }
+}
+
+/// Response body keeping its request concurrency permit until it is fully
+/// sent, or dropped.
+#[derive(Debug)]
+pub struct PermitBody<B> {
+    body: B,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<B: Default> Default for PermitBody<B> {
+    fn default() -> Self {
+        PermitBody {
+            body: B::default(),
+            _permit: None,
+        }
+    }
+}
+
+impl<B: Body + Unpin> Body for PermitBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const LIMIT: RateLimit = RateLimit {
+        per_second: 2.0,
+        burst: 3,
+    };
+
+    #[test]
+    // checks a bucket allows bursts, then refills at the limit rate.
+    fn bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::full(&LIMIT, start);
+        assert_eq!(3.0, bucket.tokens);
+        for _ in 0..3 {
+            assert!(bucket.try_take(&LIMIT, start));
+        }
+        assert!(!bucket.try_take(&LIMIT, start));
+        assert!(!bucket.has_token(&LIMIT, start));
+
+        // a token every half second.
+        let later = start + Duration::from_millis(400);
+        assert!(!bucket.try_take(&LIMIT, later));
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take(&LIMIT, later));
+        assert!(!bucket.try_take(&LIMIT, later));
+
+        // never more than the burst.
+        let later = start + Duration::from_secs(60);
+        bucket.refill(&LIMIT, later);
+        assert_eq!(3.0, bucket.tokens);
+        for _ in 0..3 {
+            assert!(bucket.try_take(&LIMIT, later));
+        }
+        assert!(!bucket.try_take(&LIMIT, later));
+    }
+
+    /// take a token of a peer, the other limits letting it through.
+    fn take(limiter: &PeerLimiter, peer: IpAddr, now: Instant) -> bool {
+        limiter.try_take(peer, now, || Ok(())).is_ok()
+    }
+
+    #[test]
+    // checks each peer has its own bucket.
+    fn peer_buckets() {
+        let limiter = PeerLimiter::new(LIMIT);
+        let now = Instant::now();
+        let alice: IpAddr = "10.0.0.1".parse().unwrap();
+        let bob: IpAddr = "10.0.0.2".parse().unwrap();
+        for _ in 0..3 {
+            assert!(take(&limiter, alice, now));
+        }
+        assert!(!take(&limiter, alice, now));
+        assert!(take(&limiter, bob, now));
+    }
+
+    #[test]
+    // checks a spray of new peers arriving at once only makes the least
+    // recently seen ones forgotten.
+    fn peer_spray() {
+        let limiter = PeerLimiter::new(LIMIT);
+        let now = Instant::now();
+        let alice: IpAddr = "10.0.0.1".parse().unwrap();
+        let bob: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(take(&limiter, bob, now));
+        for _ in 0..3 {
+            assert!(take(&limiter, alice, now));
+        }
+
+        let sprayed = |range: std::ops::Range<u32>| {
+            for i in range {
+                take(&limiter, IpAddr::from((0x0b00_0000 + i).to_be_bytes()), now);
+            }
+        };
+        sprayed(0..MAX_PEERS as u32 / 2);
+        // alice is still remembered, and seen again.
+        assert!(!take(&limiter, alice, now));
+        sprayed(MAX_PEERS as u32 / 2..MAX_PEERS as u32);
+
+        let peers = limiter.peers.lock().unwrap();
+        assert_eq!(MAX_PEERS, peers.buckets.len());
+        assert_eq!(MAX_PEERS, peers.by_use.len());
+        assert!(peers.buckets.contains_key(&alice));
+        assert!(!peers.buckets.contains_key(&bob));
+        drop(peers);
+        assert!(!take(&limiter, alice, now));
+    }
+
+    #[test]
+    // checks a request rejected by another limit does not spend a peer token.
+    fn peer_token_kept_on_rejection() {
+        let limiter = PeerLimiter::new(LIMIT);
+        let now = Instant::now();
+        let alice: IpAddr = "10.0.0.1".parse().unwrap();
+        for _ in 0..5 {
+            let rejected = limiter.try_take(alice, now, || {
+                Err(Status::resource_exhausted("too many requests"))
+            });
+            assert_eq!("too many requests", rejected.unwrap_err().message());
+        }
+        for _ in 0..3 {
+            assert!(take(&limiter, alice, now));
+        }
+        assert!(!take(&limiter, alice, now));
+    }
+
+    #[test]
+    // checks the health and reflection services are not limited.
+    fn unlimited_paths() {
+        assert!(is_unlimited("/grpc.health.v1.Health/Check"));
+        assert!(is_unlimited("/grpc.health.v1.Health/Watch"));
+        assert!(is_unlimited(
+            "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo"
+        ));
+        assert!(is_unlimited(
+            "/grpc.reflection.v1.ServerReflection/ServerReflectionInfo"
+        ));
+        assert!(!is_unlimited("/echo.v1.EchoService/Say"));
+        assert!(!is_unlimited("/grpc.health.v1.Health"));
+        assert!(!is_unlimited("/grpc.health.v1.HealthX/Check"));
+    }
+}
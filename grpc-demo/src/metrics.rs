@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes};
+use http::{header, HeaderValue, Method, StatusCode};
+use http_body::{Body, Frame, SizeHint};
+use http_body_util::Full;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tonic::{Code, Status};
+use tower::{Layer, Service};
+
+// upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+// number of methods measured, the calls of any other being measured together.
+const MAX_METHODS: usize = 256;
+
+// path of the metrics, on their HTTP port.
+const METRICS_PATH: &str = "/metrics";
+
+/// name of a status code, as in the gRPC specification.
+fn code_name(code: Code) -> &'static str {
+    match code {
+        Code::Ok => "OK",
+        Code::Cancelled => "CANCELLED",
+        Code::Unknown => "UNKNOWN",
+        Code::InvalidArgument => "INVALID_ARGUMENT",
+        Code::DeadlineExceeded => "DEADLINE_EXCEEDED",
+        Code::NotFound => "NOT_FOUND",
+        Code::AlreadyExists => "ALREADY_EXISTS",
+        Code::PermissionDenied => "PERMISSION_DENIED",
+        Code::ResourceExhausted => "RESOURCE_EXHAUSTED",
+        Code::FailedPrecondition => "FAILED_PRECONDITION",
+        Code::Aborted => "ABORTED",
+        Code::OutOfRange => "OUT_OF_RANGE",
+        Code::Unimplemented => "UNIMPLEMENTED",
+        Code::Internal => "INTERNAL",
+        Code::Unavailable => "UNAVAILABLE",
+        Code::DataLoss => "DATA_LOSS",
+        Code::Unauthenticated => "UNAUTHENTICATED",
+    }
+}
+
+/// service and method names of a gRPC path, like `/echo.v1.EchoService/Say`.
+fn method_labels(path: &str) -> (String, String) {
+    match path.strip_prefix('/').and_then(|path| path.split_once('/')) {
+        Some((service, method)) if !service.is_empty() && !method.contains('/') => {
+            (service.to_string(), method.to_string())
+        }
+        _ => ("unknown".into(), "unknown".into()),
+    }
+}
+
+/// escape a Prometheus label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Measures of the calls of a method.
+#[derive(Debug, Default)]
+struct MethodMetrics {
+    in_flight: u64,
+    // number of finished calls, by status code.
+    handled: BTreeMap<i32, u64>,
+    // number of calls in each latency bucket, not cumulated.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_sum: f64,
+}
+
+/// Per-method RPC metrics: calls by status code, latency histograms and
+/// calls in flight. Clones share the same metrics.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    // keyed by service and method names.
+    methods: Arc<Mutex<BTreeMap<(String, String), MethodMetrics>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            ..Default::default()
+        }
+    }
+
+    /// count a call starting, and return the key of its method.
+    fn start(&self, path: &str) -> (String, String) {
+        let mut methods = self.methods.lock().unwrap();
+        let mut key = method_labels(path);
+        // unknown methods must not blow up the metrics.
+        if !methods.contains_key(&key) && methods.len() >= MAX_METHODS {
+            key = ("unknown".into(), "unknown".into());
+        }
+        methods.entry(key.clone()).or_default().in_flight += 1;
+        key
+    }
+
+    /// count a call ending.
+    fn finish(&self, key: &(String, String), code: Code, latency: Duration) {
+        let mut methods = self.methods.lock().unwrap();
+        let method = methods.entry(key.clone()).or_default();
+        method.in_flight = method.in_flight.saturating_sub(1);
+        *method.handled.entry(code as i32).or_default() += 1;
+        let latency = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| latency <= *le) {
+            method.buckets[bucket] += 1;
+        }
+        method.latency_sum += latency;
+    }
+
+    /// the metrics, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let methods = self.methods.lock().unwrap();
+        let labels = |(service, method): &(String, String)| {
+            format!(
+                "grpc_service=\"{}\",grpc_method=\"{}\"",
+                escape(service),
+                escape(method)
+            )
+        };
+        // writing to a string never fails.
+        let mut text = String::new();
+        text.push_str(
+            "# HELP grpc_server_handled_total Number of RPCs completed, by status code.\n",
+        );
+        text.push_str("# TYPE grpc_server_handled_total counter\n");
+        for (key, method) in methods.iter() {
+            for (code, count) in method.handled.iter() {
+                let _ = writeln!(
+                    text,
+                    "grpc_server_handled_total{{{},grpc_code=\"{}\"}} {}",
+                    labels(key),
+                    code_name(Code::from(*code)),
+                    count
+                );
+            }
+        }
+
+        text.push_str("# HELP grpc_server_handling_seconds Duration of the completed RPCs, until their last message.\n");
+        text.push_str("# TYPE grpc_server_handling_seconds histogram\n");
+        for (key, method) in methods.iter() {
+            let labels = labels(key);
+            let mut cumulated = 0;
+            for (le, count) in LATENCY_BUCKETS.iter().zip(method.buckets.iter()) {
+                cumulated += count;
+                let _ = writeln!(
+                    text,
+                    "grpc_server_handling_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, cumulated
+                );
+            }
+            let count = method.handled.values().sum::<u64>();
+            let _ = writeln!(
+                text,
+                "grpc_server_handling_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, count
+            );
+            let _ = writeln!(
+                text,
+                "grpc_server_handling_seconds_sum{{{}}} {}",
+                labels, method.latency_sum
+            );
+            let _ = writeln!(
+                text,
+                "grpc_server_handling_seconds_count{{{}}} {}",
+                labels, count
+            );
+        }
+
+        text.push_str("# HELP grpc_server_in_flight Number of RPCs running.\n");
+        text.push_str("# TYPE grpc_server_in_flight gauge\n");
+        for (key, method) in methods.iter() {
+            let _ = writeln!(
+                text,
+                "grpc_server_in_flight{{{}}} {}",
+                labels(key),
+                method.in_flight
+            );
+        }
+        text
+    }
+
+    /// answer a request to the metrics HTTP port.
+    fn scrape<B>(&self, request: &http::Request<B>) -> http::Response<Full<Bytes>> {
+        let mut response = http::Response::new(Full::default());
+        if request.uri().path() != METRICS_PATH {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+        } else if request.method() != Method::GET {
+            *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+            response
+                .headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static("GET"));
+        } else {
+            *response.body_mut() = Full::new(Bytes::from(self.render()));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+        }
+        response
+    }
+}
+
+/// serve the metrics to Prometheus over HTTP/1, on `GET /metrics`.
+pub async fn serve(listener: TcpListener, metrics: Metrics) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                // e.g. too many open files: wait for some to be closed.
+                tracing::warn!(error = %err, "cannot accept a metrics connection");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let response = metrics.scrape(&request);
+                async move { Ok::<_, Infallible>(response) }
+            });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!(error = %err, "metrics connection failed");
+            }
+        });
+    }
+}
+
+/// Layer measuring every RPC into `Metrics`.
+#[derive(Debug, Clone)]
+pub struct MetricsLayer {
+    metrics: Metrics,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Metrics) -> Self {
+        MetricsLayer { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Metrics,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<MeasuredBody<ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let mut rpc = Rpc {
+            key: self.metrics.start(request.uri().path()),
+            metrics: self.metrics.clone(),
+            started_at: Instant::now(),
+            finished: false,
+        };
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await?;
+            // responses without any message carry their status in the headers.
+            if let Some(status) = Status::from_header_map(response.headers()) {
+                rpc.finish(status.code());
+            }
+            Ok(response.map(|body| MeasuredBody { body, rpc }))
+        })
+    }
+}
+
+/// An RPC being measured. It is measured as cancelled if dropped before its
+/// status.
+struct Rpc {
+    metrics: Metrics,
+    key: (String, String),
+    started_at: Instant,
+    finished: bool,
+}
+
+impl Rpc {
+    fn finish(&mut self, code: Code) {
+        if !self.finished {
+            self.finished = true;
+            self.metrics
+                .finish(&self.key, code, self.started_at.elapsed());
+        }
+    }
+}
+
+impl Drop for Rpc {
+    fn drop(&mut self) {
+        self.finish(Code::Cancelled);
+    }
+}
+
+/// Response body measuring its RPC once its status is sent, or dropped.
+pub struct MeasuredBody<B> {
+    body: B,
+    rpc: Rpc,
+}
+
+impl<B> Body for MeasuredBody<B>
+where
+    B: Body + Unpin,
+    B::Data: Buf,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.body).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(status) = frame.trailers_ref().and_then(Status::from_header_map) {
+                self.rpc.finish(status.code());
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echo::v1::echo_service_client::EchoServiceClient;
+    use crate::echo::v1::echo_service_server::EchoServiceServer;
+    use crate::echo::v1::SayRequest;
+    use crate::service::MyEchoService;
+    use http_body_util::BodyExt;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+    use tonic::transport::Server;
+
+    #[test]
+    // checks the labels of the gRPC paths.
+    fn labels() {
+        assert_eq!(
+            ("echo.v1.EchoService".to_string(), "Say".to_string()),
+            method_labels("/echo.v1.EchoService/Say")
+        );
+        for path in ["/", "/v1/say/more", "//Say"].iter() {
+            assert_eq!(("unknown".into(), "unknown".into()), method_labels(path));
+        }
+    }
+
+    /// GET a path of the metrics port, and return its status and body.
+    async fn get(addr: std::net::SocketAddr, path: &str) -> (StatusCode, String) {
+        let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+        let uri = format!("http://{}{}", addr, path).parse().unwrap();
+        let response = client.get(uri).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    // checks the metrics endpoint counts the calls by status, their latency,
+    // and the ones in flight.
+    async fn scrape() {
+        let metrics = Metrics::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .layer(MetricsLayer::new(metrics.clone()))
+                .add_service(EchoServiceServer::new(MyEchoService::default()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metrics_addr = metrics_listener.local_addr().unwrap();
+        tokio::spawn(serve(metrics_listener, metrics));
+
+        let mut client = EchoServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        for message in ["one", "two", ""].iter() {
+            let request = SayRequest {
+                message: message.to_string(),
+            };
+            let _ = client.say(request).await;
+        }
+        let (requests, rx) = mpsc::channel::<SayRequest>(1);
+        let _chat = client.chat(ReceiverStream::new(rx)).await.unwrap();
+        let _requests = requests;
+
+        let (status, text) = get(metrics_addr, "/metrics").await;
+        assert_eq!(StatusCode::OK, status);
+        let say = r#"grpc_service="echo.v1.EchoService",grpc_method="Say""#;
+        let chat = r#"grpc_service="echo.v1.EchoService",grpc_method="Chat""#;
+        for line in [
+            format!(r#"grpc_server_handled_total{{{},grpc_code="OK"}} 2"#, say),
+            format!(
+                r#"grpc_server_handled_total{{{},grpc_code="INVALID_ARGUMENT"}} 1"#,
+                say
+            ),
+            format!(
+                r#"grpc_server_handling_seconds_bucket{{{},le="+Inf"}} 3"#,
+                say
+            ),
+            format!("grpc_server_handling_seconds_count{{{}}} 3", say),
+            format!("grpc_server_in_flight{{{}}} 0", say),
+            format!("grpc_server_in_flight{{{}}} 1", chat),
+        ]
+        .iter()
+        {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} not in:\n{}",
+                line,
+                text
+            );
+        }
+
+        assert_eq!(StatusCode::NOT_FOUND, get(metrics_addr, "/").await.0);
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tonic::metadata::MetadataMap;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::echo::v1::echo_service_server::{EchoService, EchoServiceServer};
+use crate::echo::v1::{SayManyRequest, SayRequest, SayResponse};
+use crate::legacy::Legacy;
+use crate::service::{hello, STREAM_BUFFER};
+
+/// An RPC of the echo service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Say,
+    SayMany,
+    CollectSay,
+    Chat,
+}
+
+/// Scripted answer to a call, or for `Chat` to one of its messages.
+#[derive(Debug, Clone, Default)]
+pub struct Reply {
+    messages: Vec<String>,
+    error: Option<Status>,
+    delay: Duration,
+}
+
+impl Reply {
+    /// answer a single message.
+    pub fn message(message: impl Into<String>) -> Self {
+        Reply::messages(vec![message.into()])
+    }
+
+    /// answer several messages, for the streaming RPCs.
+    pub fn messages(messages: Vec<String>) -> Self {
+        Reply {
+            messages,
+            ..Default::default()
+        }
+    }
+
+    /// fail right away.
+    pub fn error(status: Status) -> Self {
+        Reply {
+            error: Some(status),
+            ..Default::default()
+        }
+    }
+
+    /// fail once the messages are sent. Unary calls fail without their
+    /// message.
+    pub fn then_error(mut self, status: Status) -> Self {
+        self.error = Some(status);
+        self
+    }
+
+    /// wait before answering.
+    pub fn after(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// A call received by the mock.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Call {
+    Say(SayRequest),
+    SayMany(SayManyRequest),
+    CollectSay(Vec<SayRequest>),
+    /// the messages received so far.
+    Chat(Vec<SayRequest>),
+}
+
+/// A call received by the mock, with its metadata.
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub call: Call,
+    pub metadata: MetadataMap,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // replies of the next calls, for each method.
+    replies: HashMap<Method, VecDeque<Reply>>,
+    calls: Vec<Recorded>,
+}
+
+/// Programmable echo service, for the tests of its clients. Each call takes
+/// the next reply scripted for its method or, once they ran out, answers like
+/// the real service. Every call is recorded. Clones share the same script and
+/// records, so a test can keep one while the other is served.
+#[derive(Debug, Clone, Default)]
+pub struct MockEcho {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockEcho {
+    pub fn new() -> Self {
+        MockEcho {
+            ..Default::default()
+        }
+    }
+
+    /// script the reply of the next call of a method, after the ones already
+    /// scripted.
+    pub fn reply(self, method: Method, reply: Reply) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .replies
+            .entry(method)
+            .or_default()
+            .push_back(reply);
+        self
+    }
+
+    /// the calls received so far, oldest first.
+    pub fn calls(&self) -> Vec<Recorded> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// record a call, and return its index.
+    fn record(&self, call: Call, metadata: &MetadataMap) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(Recorded {
+            call,
+            metadata: metadata.clone(),
+        });
+        state.calls.len() - 1
+    }
+
+    fn next_reply(&self, method: Method) -> Option<Reply> {
+        self.state
+            .lock()
+            .unwrap()
+            .replies
+            .get_mut(&method)
+            .and_then(|replies| replies.pop_front())
+    }
+}
+
+/// answer a unary call with a reply, waiting first for its delay.
+async fn unary(reply: Reply) -> Result<Response<SayResponse>, Status> {
+    tokio::time::sleep(reply.delay).await;
+    if let Some(status) = reply.error {
+        return Err(status);
+    }
+    match reply.messages.into_iter().next() {
+        Some(message) => Ok(Response::new(SayResponse { message })),
+        None => Err(Status::internal("no message scripted")),
+    }
+}
+
+/// send the messages of a reply on a stream, then its error if any. Return
+/// whether the stream must go on.
+async fn stream(reply: Reply, tx: &mpsc::Sender<Result<SayResponse, Status>>) -> bool {
+    tokio::time::sleep(reply.delay).await;
+    for message in reply.messages {
+        if tx.send(Ok(SayResponse { message })).await.is_err() {
+            return false;
+        }
+    }
+    match reply.error {
+        Some(status) => {
+            let _ = tx.send(Err(status)).await;
+            false
+        }
+        None => true,
+    }
+}
+
+#[tonic::async_trait]
+impl EchoService for MockEcho {
+    async fn say(&self, request: Request<SayRequest>) -> Result<Response<SayResponse>, Status> {
+        let call = Call::Say(request.get_ref().clone());
+        self.record(call, request.metadata());
+        let reply = self
+            .next_reply(Method::Say)
+            .unwrap_or_else(|| Reply::message(hello(&request.get_ref().message).message));
+        unary(reply).await
+    }
+
+    type SayManyStream = ReceiverStream<Result<SayResponse, Status>>;
+
+    async fn say_many(
+        &self,
+        request: Request<SayManyRequest>,
+    ) -> Result<Response<Self::SayManyStream>, Status> {
+        self.record(Call::SayMany(request.get_ref().clone()), request.metadata());
+        let request = request.into_inner();
+        let reply = self.next_reply(Method::SayMany).unwrap_or_else(|| {
+            Reply::messages(
+                (0..request.count)
+                    .map(|idx| hello(&format!("{} #{}", request.message, idx)).message)
+                    .collect(),
+            )
+        });
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move { stream(reply, &tx).await });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn collect_say(
+        &self,
+        request: Request<Streaming<SayRequest>>,
+    ) -> Result<Response<SayResponse>, Status> {
+        let metadata = request.metadata().clone();
+        let mut stream = request.into_inner();
+        let mut requests = Vec::new();
+        while let Some(req) = stream.next().await {
+            requests.push(req?);
+        }
+        let messages = requests
+            .iter()
+            .map(|req| req.message.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.record(Call::CollectSay(requests), &metadata);
+        let reply = self
+            .next_reply(Method::CollectSay)
+            .unwrap_or_else(|| Reply::message(hello(&messages).message));
+        unary(reply).await
+    }
+
+    type ChatStream = ReceiverStream<Result<SayResponse, Status>>;
+
+    async fn chat(
+        &self,
+        request: Request<Streaming<SayRequest>>,
+    ) -> Result<Response<Self::ChatStream>, Status> {
+        let index = self.record(Call::Chat(Vec::new()), request.metadata());
+        let mut requests = request.into_inner();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let mock = self.clone();
+        tokio::spawn(async move {
+            while let Some(req) = requests.next().await {
+                let req = match req {
+                    Ok(req) => req,
+                    Err(_) => break,
+                };
+                if let Call::Chat(received) = &mut mock.state.lock().unwrap().calls[index].call {
+                    received.push(req.clone());
+                }
+                let reply = mock
+                    .next_reply(Method::Chat)
+                    .unwrap_or_else(|| Reply::message(hello(&req.message).message));
+                if !stream(reply, &tx).await {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// A mock served on a local port, until dropped.
+#[derive(Debug)]
+pub struct MockServer {
+    pub addr: SocketAddr,
+    pub mock: MockEcho,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl MockServer {
+    /// serve a mock on an ephemeral port of the loopback interface, under
+    /// the v1 and legacy service names.
+    pub async fn spawn(mock: MockEcho) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let incoming = TcpIncoming::from(listener).with_nodelay(Some(true));
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        tokio::spawn(
+            Server::builder()
+                .add_service(Legacy::new(EchoServiceServer::new(mock.clone())))
+                .add_service(EchoServiceServer::new(mock.clone()))
+                .serve_with_incoming_shutdown(incoming, async {
+                    let _ = stopped.await;
+                }),
+        );
+        Ok(MockServer {
+            addr,
+            mock,
+            _shutdown: shutdown,
+        })
+    }
+
+    /// uri of the server, for the clients.
+    pub fn uri(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echo::v1::echo_service_client::EchoServiceClient;
+    use std::time::Instant;
+    use tonic::transport::Channel;
+    use tonic::Code;
+
+    async fn client(server: &MockServer) -> EchoServiceClient<Channel> {
+        EchoServiceClient::connect(server.uri()).await.unwrap()
+    }
+
+    fn say(message: &str) -> SayRequest {
+        SayRequest {
+            message: message.into(),
+        }
+    }
+
+    #[tokio::test]
+    // checks scripted replies, errors and delays are played in order, then
+    // the real answers.
+    async fn scripted() {
+        let mock = MockEcho::new()
+            .reply(Method::Say, Reply::message("scripted"))
+            .reply(Method::Say, Reply::error(Status::unavailable("down")))
+            .reply(
+                Method::Say,
+                Reply::message("late").after(Duration::from_millis(100)),
+            );
+        let server = MockServer::spawn(mock).await.unwrap();
+        let mut client = client(&server).await;
+
+        let resp = client.say(say("Tonic")).await.unwrap();
+        assert_eq!("scripted", resp.into_inner().message);
+        let status = client.say(say("Tonic")).await.unwrap_err();
+        assert_eq!(Code::Unavailable, status.code());
+        let started_at = Instant::now();
+        let resp = client.say(say("Tonic")).await.unwrap();
+        assert_eq!("late", resp.into_inner().message);
+        assert!(started_at.elapsed() >= Duration::from_millis(100));
+        let resp = client.say(say("Tonic")).await.unwrap();
+        assert_eq!("Hello Tonic!", resp.into_inner().message);
+    }
+
+    #[tokio::test]
+    // checks streams are scripted, and failed after their messages.
+    async fn streams() {
+        let mock = MockEcho::new()
+            .reply(
+                Method::SayMany,
+                Reply::messages(vec!["a".into(), "b".into()]).then_error(Status::aborted("cut")),
+            )
+            .reply(Method::Chat, Reply::message("first"))
+            .reply(Method::Chat, Reply::error(Status::internal("boom")));
+        let server = MockServer::spawn(mock).await.unwrap();
+        let mut client = client(&server).await;
+
+        let request = SayManyRequest {
+            message: "Tonic".into(),
+            count: 5,
+        };
+        let mut stream = client.say_many(request).await.unwrap().into_inner();
+        assert_eq!("a", stream.message().await.unwrap().unwrap().message);
+        assert_eq!("b", stream.message().await.unwrap().unwrap().message);
+        assert_eq!(Code::Aborted, stream.message().await.unwrap_err().code());
+
+        let requests = vec![say("one"), say("two")];
+        let mut stream = client
+            .chat(tokio_stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!("first", stream.message().await.unwrap().unwrap().message);
+        assert_eq!(Code::Internal, stream.message().await.unwrap_err().code());
+    }
+
+    #[tokio::test]
+    // checks the calls are recorded with their metadata.
+    async fn recorded() {
+        let server = MockServer::spawn(MockEcho::new()).await.unwrap();
+        let mut client = client(&server).await;
+
+        let mut request = Request::new(say("Tonic"));
+        request
+            .metadata_mut()
+            .insert("x-tenant", "acme".parse().unwrap());
+        client.say(request).await.unwrap();
+        let requests = vec![say("one"), say("two")];
+        let resp = client
+            .collect_say(tokio_stream::iter(requests.clone()))
+            .await
+            .unwrap();
+        assert_eq!("Hello one, two!", resp.into_inner().message);
+
+        let calls = server.mock.calls();
+        assert_eq!(2, calls.len());
+        assert_eq!(Call::Say(say("Tonic")), calls[0].call);
+        assert_eq!("acme", calls[0].metadata.get("x-tenant").unwrap());
+        assert_eq!(Call::CollectSay(requests), calls[1].call);
+    }
+}
@@ -0,0 +1,186 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use http::{HeaderMap, HeaderValue};
+use tower::{Layer, Service};
+use tracing::Span;
+
+/// metadata of the request id, generated by the server if missing.
+pub const REQUEST_ID: &str = "x-request-id";
+/// metadata of the tenant the request is made for.
+pub const TENANT: &str = "x-tenant";
+/// metadata of the locale of the caller, as a BCP 47 language tag.
+pub const LOCALE: &str = "x-locale";
+
+// longest request id accepted, generated ones are 32 characters long.
+const MAX_REQUEST_ID_LEN: usize = 128;
+const MAX_TENANT_LEN: usize = 64;
+// the longest tags in practice, e.g. `zh-Hant-CN-x-private1`, are far shorter.
+const MAX_LOCALE_LEN: usize = 35;
+
+/// Metadata propagated from the request to its response. Available in the
+/// request extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestMetadata {
+    pub request_id: String,
+    pub tenant: Option<String>,
+    pub locale: Option<String>,
+}
+
+/// the value of a header, if it is made of up to `max_len` ASCII letters,
+/// digits and the given punctuation.
+fn header<'a>(
+    headers: &'a HeaderMap,
+    name: &str,
+    max_len: usize,
+    punctuation: &str,
+) -> Option<&'a str> {
+    let value = headers.get(name)?.to_str().ok()?;
+    let valid = !value.is_empty()
+        && value.len() <= max_len
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || punctuation.contains(c));
+    if valid {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+impl RequestMetadata {
+    /// read the metadata of request headers. A new request id is generated
+    /// if it is missing or invalid, and an invalid tenant or locale is
+    /// ignored.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let request_id = match header(headers, REQUEST_ID, MAX_REQUEST_ID_LEN, "-_.:") {
+            Some(request_id) => request_id.to_string(),
+            None => format!("{:032x}", rand::random::<u128>()),
+        };
+        RequestMetadata {
+            request_id,
+            tenant: header(headers, TENANT, MAX_TENANT_LEN, "-_.").map(String::from),
+            locale: header(headers, LOCALE, MAX_LOCALE_LEN, "-").map(String::from),
+        }
+    }
+
+    /// write the metadata in response headers.
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        // the values were checked to be valid headers.
+        let mut insert = |name, value: &str| {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        };
+        insert(REQUEST_ID, &self.request_id);
+        if let Some(tenant) = &self.tenant {
+            insert(TENANT, tenant);
+        }
+        if let Some(locale) = &self.locale {
+            insert(LOCALE, locale);
+        }
+    }
+}
+
+/// Layer putting the `RequestMetadata` of every request in its extensions
+/// and in the current span, and echoing it in the response metadata. Errors
+/// carry it too, their status being sent in the response headers.
+#[derive(Debug, Clone, Default)]
+pub struct PropagationLayer;
+
+impl<S> Layer<S> for PropagationLayer {
+    type Service = PropagationService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PropagationService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PropagationService<S> {
+    inner: S,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for PropagationService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<ReqBody>) -> Self::Future {
+        let metadata = RequestMetadata::from_headers(request.headers());
+        let span = Span::current();
+        span.record("request_id", metadata.request_id.as_str());
+        if let Some(tenant) = &metadata.tenant {
+            span.record("tenant", tenant.as_str());
+        }
+        if let Some(locale) = &metadata.locale {
+            span.record("locale", locale.as_str());
+        }
+        request.extensions_mut().insert(metadata.clone());
+
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = response.await?;
+            metadata.add_headers(response.headers_mut());
+            Ok(response)
+        })
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(entries: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in entries {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    // checks valid metadata is kept, and invalid one replaced or ignored.
+    fn from_headers() {
+        let metadata = RequestMetadata::from_headers(&headers(&[
+            (REQUEST_ID, "req-42"),
+            (TENANT, "acme"),
+            (LOCALE, "fr-CA"),
+        ]));
+        assert_eq!(
+            RequestMetadata {
+                request_id: "req-42".into(),
+                tenant: Some("acme".into()),
+                locale: Some("fr-CA".into()),
+            },
+            metadata
+        );
+
+        let metadata = RequestMetadata::from_headers(&headers(&[
+            (REQUEST_ID, "no spaces"),
+            (TENANT, "a/b"),
+            (LOCALE, "fr_CA"),
+        ]));
+        assert_eq!(32, metadata.request_id.len());
+        assert_eq!(None, metadata.tenant);
+        assert_eq!(None, metadata.locale);
+
+        // every request gets its own id.
+        let first = RequestMetadata::from_headers(&HeaderMap::new());
+        let second = RequestMetadata::from_headers(&HeaderMap::new());
+        assert_ne!(first.request_id, second.request_id);
+    }
+}
@@ -0,0 +1,499 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use tonic::codegen::InterceptedService;
+use tonic::{Code, Request, Status, Streaming};
+
+use crate::balance::BalancedChannel;
+use crate::client_config::Authorization;
+use crate::echo::v1::echo_service_client::EchoServiceClient;
+use crate::echo::v1::{SayManyRequest, SayRequest, SayResponse};
+
+/// The echo client, as connected by the client config.
+pub type Client = EchoServiceClient<InterceptedService<BalancedChannel, Authorization>>;
+
+/// How failed calls are retried.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// number of attempts of a call, the first one included.
+    pub max_attempts: u32,
+    /// longest delay before the first retry.
+    pub initial_backoff: Duration,
+    /// longest delay before any retry.
+    pub max_backoff: Duration,
+    /// growth of the delay after each failure.
+    pub multiplier: f64,
+    /// status codes worth retrying.
+    pub retryable: Vec<Code>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            multiplier: 2.0,
+            // the server was unreachable, or asked to retry: the request
+            // itself is fine. Retrying RESOURCE_EXHAUSTED would only add load
+            // to a server rate limiting its clients.
+            retryable: vec![Code::Unavailable, Code::Aborted],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// whether a failed call may succeed if retried.
+    pub fn is_retryable(&self, status: &Status) -> bool {
+        self.retryable.contains(&status.code())
+    }
+
+    /// check the policy retries at least once, with delays which never shrink.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("at least one attempt is needed".into());
+        }
+        if self.initial_backoff > self.max_backoff {
+            return Err("the initial backoff must not exceed the maximum backoff".into());
+        }
+        // also refuses NaN.
+        if !(self.multiplier >= 1.0 && self.multiplier.is_finite()) {
+            return Err("the backoff multiplier must be at least 1".into());
+        }
+        Ok(())
+    }
+
+    /// delay before retrying after some failures. It grows exponentially with
+    /// the failures, and is picked at random below that, so clients failing
+    /// together don't retry together.
+    pub fn backoff(&self, failures: u32, rng: &mut impl Rng) -> Duration {
+        let exponent = failures.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let growth = self.multiplier.powi(exponent);
+        // capped as a float, as the delay may grow too large for a duration.
+        let cap = Duration::try_from_secs_f64(self.initial_backoff.as_secs_f64() * growth)
+            .map_or(self.max_backoff, |cap| cap.min(self.max_backoff));
+        rng.gen_range(Duration::from_secs(0)..=cap)
+    }
+}
+
+// how early servers may cancel a call before its deadline, as the timeout
+// they are sent is rounded.
+const DEADLINE_SLACK: Duration = Duration::from_millis(20);
+
+/// make a call until it succeeds, fails with a non retryable status, runs out
+/// of attempts, or runs out of time. Each attempt is given the time left
+/// before the deadline, if any.
+pub async fn retry<T, F, Fut>(
+    policy: &RetryPolicy,
+    deadline: Option<Duration>,
+    mut attempt: F,
+) -> Result<T, Status>
+where
+    F: FnMut(Option<Duration>) -> Fut,
+    Fut: Future<Output = Result<T, Status>>,
+{
+    let started_at = Instant::now();
+    let time_left = || match deadline {
+        Some(deadline) => match deadline.checked_sub(started_at.elapsed()) {
+            Some(left) if !left.is_zero() => Ok(Some(left)),
+            _ => Err(Status::deadline_exceeded("call deadline exceeded")),
+        },
+        None => Ok(None),
+    };
+
+    // whether the deadline is about reached.
+    let deadline_reached =
+        || matches!(deadline, Some(deadline) if started_at.elapsed() + DEADLINE_SLACK >= deadline);
+
+    let mut failures = 0;
+    loop {
+        let left = time_left()?;
+        let result = match left {
+            // the server may not honor the deadline, or not be reachable.
+            Some(left) => match tokio::time::timeout(left, attempt(Some(left))).await {
+                Ok(result) => result,
+                Err(_) => Err(Status::deadline_exceeded("call deadline exceeded")),
+            },
+            None => attempt(None).await,
+        };
+        let status = match result {
+            Ok(response) => return Ok(response),
+            // servers cancel the calls running past the deadline they were
+            // sent, maybe a bit early as it is rounded. Other cancellations,
+            // e.g. by a server shutting down, are kept as is.
+            Err(status) if status.code() == Code::Cancelled && deadline_reached() => {
+                return Err(Status::deadline_exceeded("call deadline exceeded"));
+            }
+            Err(status) => status,
+        };
+
+        failures += 1;
+        if failures >= policy.max_attempts || !policy.is_retryable(&status) {
+            return Err(status);
+        }
+        let backoff = policy.backoff(failures, &mut rand::thread_rng());
+        // no time left for another attempt.
+        if matches!(time_left()?, Some(left) if left <= backoff) {
+            return Err(status);
+        }
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// Echo client retrying the calls that failed, within a deadline. Cheap to
+/// clone: every clone shares the same connection, which is reestablished if
+/// lost.
+#[derive(Debug, Clone)]
+pub struct RetryingClient {
+    client: Client,
+    policy: RetryPolicy,
+    deadline: Option<Duration>,
+}
+
+/// build a request, telling the server how long it has to answer.
+fn request<T>(message: T, timeout: Option<Duration>) -> Request<T> {
+    let mut request = Request::new(message);
+    if let Some(timeout) = timeout {
+        request.set_timeout(timeout);
+    }
+    request
+}
+
+impl RetryingClient {
+    pub fn new(client: Client, policy: RetryPolicy, deadline: Option<Duration>) -> Self {
+        RetryingClient {
+            client,
+            policy,
+            deadline,
+        }
+    }
+
+    /// the underlying client, for calls which can't be retried, e.g. client
+    /// streaming ones.
+    pub fn client(&self) -> Client {
+        self.client.clone()
+    }
+
+    pub async fn say(&self, message: SayRequest) -> Result<SayResponse, Status> {
+        retry(&self.policy, self.deadline, |timeout| {
+            let mut client = self.client.clone();
+            let request = request(message.clone(), timeout);
+            async move { client.say(request).await }
+        })
+        .await
+        .map(|response| response.into_inner())
+    }
+
+    /// start a server stream. Only starting it is retried: the stream may
+    /// still fail later on.
+    pub async fn say_many(
+        &self,
+        message: SayManyRequest,
+    ) -> Result<Streaming<SayResponse>, Status> {
+        retry(&self.policy, self.deadline, |timeout| {
+            let mut client = self.client.clone();
+            let request = request(message.clone(), timeout);
+            async move { client.say_many(request).await }
+        })
+        .await
+        .map(|response| response.into_inner())
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_config::{ClientConfig, ClientOpt};
+    use crate::echo::v1::echo_service_server::{EchoService, EchoServiceServer};
+    use rand::SeedableRng;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tokio_stream::StreamExt;
+    use tonic::{Response, Streaming};
+
+    /// Echo server failing its first calls, and answering slowly.
+    #[derive(Debug, Default, Clone)]
+    struct FlakyEcho {
+        failures: u32,
+        code: Option<Code>,
+        delay: Duration,
+        calls: Arc<AtomicU32>,
+    }
+
+    impl FlakyEcho {
+        async fn answer(&self) -> Result<(), Status> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            match self.code {
+                Some(code) if call < self.failures => Err(Status::new(code, "flaky")),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    type ResponseStream = tokio_stream::Iter<std::vec::IntoIter<Result<SayResponse, Status>>>;
+
+    #[tonic::async_trait]
+    impl EchoService for FlakyEcho {
+        async fn say(&self, request: Request<SayRequest>) -> Result<Response<SayResponse>, Status> {
+            self.answer().await?;
+            Ok(Response::new(SayResponse {
+                message: request.into_inner().message,
+            }))
+        }
+
+        type SayManyStream = ResponseStream;
+
+        async fn say_many(
+            &self,
+            request: Request<SayManyRequest>,
+        ) -> Result<Response<Self::SayManyStream>, Status> {
+            self.answer().await?;
+            let request = request.into_inner();
+            let responses = (0..request.count)
+                .map(|_| {
+                    Ok(SayResponse {
+                        message: request.message.clone(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            Ok(Response::new(tokio_stream::iter(responses)))
+        }
+
+        async fn collect_say(
+            &self,
+            _request: Request<Streaming<SayRequest>>,
+        ) -> Result<Response<SayResponse>, Status> {
+            Err(Status::unimplemented("not flaky enough"))
+        }
+
+        type ChatStream = ResponseStream;
+
+        async fn chat(
+            &self,
+            _request: Request<Streaming<SayRequest>>,
+        ) -> Result<Response<Self::ChatStream>, Status> {
+            Err(Status::unimplemented("not flaky enough"))
+        }
+    }
+
+    /// a policy retrying quickly.
+    fn fast_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+            ..Default::default()
+        }
+    }
+
+    /// a client to an address, which may not be listening yet.
+    fn client_to(
+        addr: std::net::SocketAddr,
+        policy: RetryPolicy,
+        deadline: Option<Duration>,
+    ) -> RetryingClient {
+        let opt = ClientOpt {
+            target: Some(format!("http://{}", addr)),
+            ..Default::default()
+        };
+        let config = ClientConfig::from_opt(opt).unwrap();
+        let client = EchoServiceClient::with_interceptor(
+            config.channel().unwrap(),
+            config.authorization().unwrap(),
+        );
+        RetryingClient::new(client, policy, deadline)
+    }
+
+    /// spawn a flaky server on a listener, and return the number of
+    /// connections it accepted.
+    fn spawn_flaky_server(listener: TcpListener, echo: FlakyEcho) -> Arc<AtomicU32> {
+        let connections = Arc::new(AtomicU32::new(0));
+        let accepted = connections.clone();
+        let incoming = TcpListenerStream::new(listener).map(move |stream| {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            stream
+        });
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(EchoServiceServer::new(echo))
+                .serve_with_incoming(incoming),
+        );
+        connections
+    }
+
+    #[test]
+    // checks policies with shrinking or misplaced delays are refused.
+    fn validate() {
+        assert!(RetryPolicy::default().validate().is_ok());
+        let invalid = [
+            RetryPolicy {
+                max_attempts: 0,
+                ..Default::default()
+            },
+            RetryPolicy {
+                initial_backoff: Duration::from_secs(3),
+                ..Default::default()
+            },
+            RetryPolicy {
+                multiplier: 0.5,
+                ..Default::default()
+            },
+            RetryPolicy {
+                multiplier: f64::NAN,
+                ..Default::default()
+            },
+        ];
+        for policy in invalid.iter() {
+            assert!(policy.validate().is_err(), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    // checks the backoff grows exponentially, up to its maximum.
+    fn backoff() {
+        let policy = RetryPolicy::default();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let caps = [100, 200, 400, 800, 1600, 2000, 2000];
+        for (failures, cap) in (1..).zip(caps.iter()) {
+            let cap = Duration::from_millis(*cap);
+            let backoffs = (0..100)
+                .map(|_| policy.backoff(failures, &mut rng))
+                .collect::<Vec<_>>();
+            assert!(backoffs.iter().all(|backoff| *backoff <= cap));
+            // jittered over the whole range.
+            assert!(backoffs.iter().any(|backoff| *backoff < cap / 4));
+            assert!(backoffs.iter().any(|backoff| *backoff > cap * 3 / 4));
+        }
+        // a long outage keeps waiting at most the longest delay.
+        for failures in [100, u32::MAX] {
+            assert!(policy.backoff(failures, &mut rng) <= policy.max_backoff);
+        }
+    }
+
+    #[tokio::test]
+    // checks retryable failures are retried over the same connection.
+    async fn retries_until_success() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let echo = FlakyEcho {
+            failures: 2,
+            code: Some(Code::Unavailable),
+            ..Default::default()
+        };
+        let calls = echo.calls.clone();
+        let connections = spawn_flaky_server(listener, echo);
+
+        let client = client_to(addr, fast_policy(4), None);
+        let request = SayRequest {
+            message: "Tonic".into(),
+        };
+        assert_eq!("Tonic", client.say(request).await.unwrap().message);
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+
+        let request = SayManyRequest {
+            message: "Tonic".into(),
+            count: 2,
+        };
+        let responses = client.say_many(request).await.unwrap();
+        assert_eq!(2, responses.collect::<Vec<_>>().await.len());
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    // checks calls are given up after the maximum number of attempts, or on
+    // a non retryable failure.
+    async fn gives_up() {
+        let cases = [
+            (Code::Unavailable, 3),
+            (Code::ResourceExhausted, 1),
+            (Code::InvalidArgument, 1),
+        ];
+        for (code, attempts) in cases.iter() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let echo = FlakyEcho {
+                failures: 10,
+                code: Some(*code),
+                ..Default::default()
+            };
+            let calls = echo.calls.clone();
+            spawn_flaky_server(listener, echo);
+
+            let client = client_to(addr, fast_policy(3), None);
+            let request = SayRequest {
+                message: "Tonic".into(),
+            };
+            let status = client.say(request).await.unwrap_err();
+            assert_eq!(*code, status.code());
+            assert_eq!(*attempts, calls.load(Ordering::SeqCst));
+        }
+    }
+
+    #[tokio::test]
+    // checks the deadline covers every attempt.
+    async fn deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let echo = FlakyEcho {
+            failures: 10,
+            code: Some(Code::Unavailable),
+            delay: Duration::from_millis(40),
+            ..Default::default()
+        };
+        spawn_flaky_server(listener, echo);
+
+        let client = client_to(addr, fast_policy(100), Some(Duration::from_millis(150)));
+        let started_at = Instant::now();
+        let request = SayRequest {
+            message: "Tonic".into(),
+        };
+        let status = client.say(request).await.unwrap_err();
+        assert!(started_at.elapsed() < Duration::from_millis(300));
+        assert!([Code::Unavailable, Code::DeadlineExceeded].contains(&status.code()));
+    }
+
+    #[tokio::test]
+    // checks calls cancelled by the server before the deadline are reported
+    // as cancelled.
+    async fn cancelled_before_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let echo = FlakyEcho {
+            failures: 10,
+            code: Some(Code::Cancelled),
+            ..Default::default()
+        };
+        spawn_flaky_server(listener, echo);
+
+        let client = client_to(addr, fast_policy(3), Some(Duration::from_secs(10)));
+        let request = SayRequest {
+            message: "Tonic".into(),
+        };
+        let status = client.say(request).await.unwrap_err();
+        assert_eq!(Code::Cancelled, status.code());
+    }
+
+    #[tokio::test]
+    // checks a server not up yet is waited for.
+    async fn server_starting_late() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let client = client_to(addr, fast_policy(20), None);
+        let request = SayRequest {
+            message: "Tonic".into(),
+        };
+        let call = tokio::spawn(async move { client.say(request).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let listener = TcpListener::bind(addr).await.unwrap();
+        spawn_flaky_server(listener, FlakyEcho::default());
+        assert_eq!("Tonic", call.await.unwrap().unwrap().message);
+    }
+}
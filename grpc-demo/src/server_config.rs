@@ -0,0 +1,606 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use structopt::StructOpt;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+use crate::auth::{AnyOf, Authenticator, HmacTokens, StaticTokens, TokenVerifier};
+use crate::compression;
+use crate::limit::{LimitLayer, RateLimit};
+use crate::telemetry::LogFormat;
+use crate::validate::{TextRules, Validator};
+
+/// Server options. Each one is taken, by order of priority, from the command
+/// line, the environment, the config file, or its default.
+#[derive(Debug, Default, StructOpt, Deserialize)]
+#[structopt(name = "server", about = "Echo gRPC server")]
+#[serde(deny_unknown_fields)]
+pub struct ServerOpt {
+    /// TOML config file, holding the same options (e.g. `listen = "unix:/tmp/echo.sock"`)
+    #[structopt(long, env = "ECHO_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on: `host:port`, or `unix:<path>` for a unix socket [default: [::1]:50051]
+    #[structopt(long, env = "ECHO_LISTEN")]
+    pub listen: Option<String>,
+    /// Maximum duration of a request, in milliseconds
+    #[structopt(long, env = "ECHO_TIMEOUT_MS")]
+    pub timeout_ms: Option<u64>,
+    /// Maximum number of concurrent requests per connection
+    #[structopt(long, env = "ECHO_CONCURRENCY_LIMIT")]
+    pub concurrency_limit: Option<usize>,
+    /// Maximum number of requests running at the same time, over all connections
+    #[structopt(long, env = "ECHO_MAX_CONCURRENT_REQUESTS")]
+    pub max_concurrent_requests: Option<usize>,
+    /// Maximum number of requests per second, from all clients
+    #[structopt(long, env = "ECHO_RATE_LIMIT")]
+    pub rate_limit: Option<f64>,
+    /// Maximum number of requests in a burst, from all clients [default: the rate limit]
+    #[structopt(long, env = "ECHO_RATE_LIMIT_BURST")]
+    pub rate_limit_burst: Option<u32>,
+    /// Maximum number of requests per second, from each client IP address
+    #[structopt(long, env = "ECHO_PEER_RATE_LIMIT")]
+    pub peer_rate_limit: Option<f64>,
+    /// Maximum number of requests in a burst, from each client IP address [default: the peer rate limit]
+    #[structopt(long, env = "ECHO_PEER_RATE_LIMIT_BURST")]
+    pub peer_rate_limit_burst: Option<u32>,
+    /// Maximum duration to let in-flight requests finish on shutdown, in milliseconds [default: 30000]
+    #[structopt(long, env = "ECHO_SHUTDOWN_DEADLINE_MS")]
+    pub shutdown_deadline_ms: Option<u64>,
+    /// Time between reporting not serving to health checks and draining on shutdown, in milliseconds [default: 5000]
+    #[structopt(long, env = "ECHO_SHUTDOWN_GRACE_MS")]
+    pub shutdown_grace_ms: Option<u64>,
+    /// PEM server certificate, enables TLS
+    #[structopt(long, env = "ECHO_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM server key
+    #[structopt(long, env = "ECHO_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    /// PEM CA certificate, enables mutual TLS: only clients with a certificate signed by this CA are accepted
+    #[structopt(long, env = "ECHO_TLS_CLIENT_CA")]
+    pub tls_client_ca: Option<PathBuf>,
+    /// Accepted bearer tokens, as `<subject>:<token>`. Enables authentication
+    #[structopt(long, env = "ECHO_AUTH_TOKENS", use_delimiter = true)]
+    pub auth_tokens: Option<Vec<String>>,
+    /// Key checking HMAC signed bearer tokens. Enables authentication
+    #[structopt(long, env = "ECHO_AUTH_HMAC_KEY", hide_env_values = true)]
+    pub auth_hmac_key: Option<String>,
+    /// Maximum length of the messages, in characters [default: 1024]
+    #[structopt(long, env = "ECHO_MAX_MESSAGE_LENGTH")]
+    pub max_message_length: Option<usize>,
+    /// Maximum number of messages a SayMany call may ask for [default: 1000]
+    #[structopt(long, env = "ECHO_MAX_SAY_MANY_COUNT")]
+    pub max_say_many_count: Option<u32>,
+    /// Words the messages must not contain, whatever their case
+    #[structopt(long, env = "ECHO_FORBIDDEN_WORDS", use_delimiter = true)]
+    pub forbidden_words: Option<Vec<String>>,
+    /// Message compressions accepted, and used for the responses when the client accepts them: `gzip`, `zstd`, or `none` [default: zstd,gzip]
+    #[structopt(long, env = "ECHO_COMPRESSION", use_delimiter = true)]
+    pub compression: Option<Vec<String>>,
+    /// Address to serve Prometheus metrics on, at `/metrics`: `host:port`. Disabled by default
+    #[structopt(long, env = "ECHO_METRICS_LISTEN")]
+    pub metrics_listen: Option<String>,
+    /// Log format: `pretty` or `json` [default: pretty]
+    #[structopt(long, env = "ECHO_LOG_FORMAT")]
+    pub log_format: Option<String>,
+    /// Print an HMAC signed token for this subject, valid for a day, and exit
+    #[structopt(long)]
+    #[serde(skip)]
+    pub issue_token: Option<String>,
+}
+
+impl ServerOpt {
+    /// read the options of a config file.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// complete the missing options with the ones of another source.
+    pub fn or(self, other: ServerOpt) -> Self {
+        ServerOpt {
+            config: self.config.or(other.config),
+            listen: self.listen.or(other.listen),
+            timeout_ms: self.timeout_ms.or(other.timeout_ms),
+            concurrency_limit: self.concurrency_limit.or(other.concurrency_limit),
+            max_concurrent_requests: self
+                .max_concurrent_requests
+                .or(other.max_concurrent_requests),
+            rate_limit: self.rate_limit.or(other.rate_limit),
+            rate_limit_burst: self.rate_limit_burst.or(other.rate_limit_burst),
+            peer_rate_limit: self.peer_rate_limit.or(other.peer_rate_limit),
+            peer_rate_limit_burst: self.peer_rate_limit_burst.or(other.peer_rate_limit_burst),
+            shutdown_deadline_ms: self.shutdown_deadline_ms.or(other.shutdown_deadline_ms),
+            shutdown_grace_ms: self.shutdown_grace_ms.or(other.shutdown_grace_ms),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            tls_client_ca: self.tls_client_ca.or(other.tls_client_ca),
+            auth_tokens: self.auth_tokens.or(other.auth_tokens),
+            auth_hmac_key: self.auth_hmac_key.or(other.auth_hmac_key),
+            max_message_length: self.max_message_length.or(other.max_message_length),
+            max_say_many_count: self.max_say_many_count.or(other.max_say_many_count),
+            forbidden_words: self.forbidden_words.or(other.forbidden_words),
+            compression: self.compression.or(other.compression),
+            metrics_listen: self.metrics_listen.or(other.metrics_listen),
+            log_format: self.log_format.or(other.log_format),
+            issue_token: self.issue_token.or(other.issue_token),
+        }
+    }
+}
+
+/// Where the server listens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(listen: &str) -> Result<Self, Self::Err> {
+        match listen.strip_prefix("unix:") {
+            Some(path) => Ok(Listen::Unix(path.into())),
+            None => Ok(Listen::Tcp(listen.parse()?)),
+        }
+    }
+}
+
+/// TLS settings of the server.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub client_ca_path: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// load the certificates and key.
+    pub fn load(&self) -> std::io::Result<ServerTlsConfig> {
+        let cert = std::fs::read(&self.cert_path)?;
+        let key = std::fs::read(&self.key_path)?;
+        let mut tls = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+        if let Some(client_ca_path) = &self.client_ca_path {
+            let client_ca = std::fs::read(client_ca_path)?;
+            tls = tls.client_ca_root(Certificate::from_pem(client_ca));
+        }
+        Ok(tls)
+    }
+}
+
+/// Authentication settings of the server.
+#[derive(Debug, Default, Clone)]
+pub struct AuthConfig {
+    // subject of each accepted token.
+    pub static_tokens: Vec<(String, String)>,
+    pub hmac_key: Option<String>,
+}
+
+impl AuthConfig {
+    /// build the request authenticator, disabled if no token is accepted.
+    pub fn authenticator(&self) -> Authenticator {
+        let mut verifiers: Vec<Box<dyn TokenVerifier>> = Vec::new();
+        if !self.static_tokens.is_empty() {
+            let mut tokens = StaticTokens::new();
+            for (subject, token) in self.static_tokens.iter() {
+                tokens = tokens.add(token.clone(), subject.clone());
+            }
+            verifiers.push(Box::new(tokens));
+        }
+        if let Some(hmac_key) = &self.hmac_key {
+            verifiers.push(Box::new(HmacTokens::new(hmac_key.as_bytes().to_vec())));
+        }
+        if verifiers.is_empty() {
+            Authenticator::disabled()
+        } else {
+            Authenticator::new(AnyOf(verifiers))
+        }
+    }
+}
+
+/// Request limits of the server.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LimitConfig {
+    pub rate_limit: Option<RateLimit>,
+    pub peer_rate_limit: Option<RateLimit>,
+    pub max_concurrent_requests: Option<usize>,
+}
+
+impl LimitConfig {
+    /// build the layer enforcing the limits.
+    pub fn layer(&self) -> LimitLayer {
+        let mut layer = LimitLayer::new();
+        if let Some(limit) = self.rate_limit {
+            layer = layer.rate_limit(limit);
+        }
+        if let Some(limit) = self.peer_rate_limit {
+            layer = layer.peer_rate_limit(limit);
+        }
+        if let Some(max) = self.max_concurrent_requests {
+            layer = layer.max_concurrent_requests(max);
+        }
+        layer
+    }
+}
+
+/// Request validation rules of the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationConfig {
+    pub max_message_length: usize,
+    pub max_say_many_count: u32,
+    pub forbidden_words: Vec<String>,
+}
+
+impl ValidationConfig {
+    /// build the validator enforcing the rules.
+    pub fn validator(&self) -> Validator {
+        let validator = Validator::new()
+            .message(TextRules {
+                min_chars: 1,
+                max_chars: self.max_message_length,
+            })
+            .max_count(self.max_say_many_count);
+        let words = self
+            .forbidden_words
+            .iter()
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase())
+            .collect::<Vec<_>>();
+        if words.is_empty() {
+            return validator;
+        }
+        validator.forbid(move |text| {
+            let text = text.to_lowercase();
+            words
+                .iter()
+                .find(|word| text.contains(word.as_str()))
+                .map(|word| format!("must not contain {:?}", word))
+        })
+    }
+}
+
+/// resolve a rate limit, bursting by default as much as a second of requests.
+fn rate_limit(
+    per_second: Option<f64>,
+    burst: Option<u32>,
+) -> Result<Option<RateLimit>, Box<dyn std::error::Error>> {
+    let per_second = match per_second {
+        Some(per_second) if per_second > 0.0 => per_second,
+        Some(_) => return Err("rate limits must be positive".into()),
+        None => return Ok(None),
+    };
+    let burst = burst.unwrap_or_else(|| per_second.ceil() as u32);
+    if burst == 0 {
+        return Err("rate limit bursts must be positive".into());
+    }
+    Ok(Some(RateLimit { per_second, burst }))
+}
+
+/// Resolved server settings.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub listen: Listen,
+    pub timeout: Option<Duration>,
+    pub concurrency_limit: Option<usize>,
+    pub limits: LimitConfig,
+    pub shutdown_deadline: Duration,
+    pub shutdown_grace: Duration,
+    pub validation: ValidationConfig,
+    pub compression: Vec<CompressionEncoding>,
+    pub tls: Option<TlsConfig>,
+    pub auth: AuthConfig,
+    pub metrics_listen: Option<SocketAddr>,
+    pub log_format: LogFormat,
+    pub issue_token: Option<String>,
+}
+
+const DEFAULT_LISTEN: &str = "[::1]:50051";
+const DEFAULT_SHUTDOWN_DEADLINE_MS: u64 = 30_000;
+// long enough for clients checking health every 5s to stop sending requests.
+const DEFAULT_SHUTDOWN_GRACE_MS: u64 = 5_000;
+const DEFAULT_MAX_MESSAGE_LENGTH: usize = 1024;
+const DEFAULT_MAX_SAY_MANY_COUNT: u32 = 1000;
+
+impl ServerConfig {
+    /// read the settings from the command line, the environment and the
+    /// config file.
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        ServerConfig::from_opt(ServerOpt::from_args())
+    }
+
+    /// resolve the settings, completing the options with the config file.
+    pub fn from_opt(opt: ServerOpt) -> Result<Self, Box<dyn std::error::Error>> {
+        let opt = match &opt.config {
+            Some(path) => {
+                let file = ServerOpt::from_file(path)?;
+                opt.or(file)
+            }
+            None => opt,
+        };
+
+        let tls = match (opt.tls_cert, opt.tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path,
+                key_path,
+                client_ca_path: opt.tls_client_ca,
+            }),
+            (None, None) => None,
+            _ => return Err("both a TLS certificate and key are needed".into()),
+        };
+
+        let mut static_tokens = Vec::new();
+        for token in opt.auth_tokens.unwrap_or_default() {
+            match token.split_once(':') {
+                Some((subject, token)) => static_tokens.push((subject.into(), token.into())),
+                None => return Err("auth tokens must be given as <subject>:<token>".into()),
+            }
+        }
+
+        Ok(ServerConfig {
+            listen: opt.listen.as_deref().unwrap_or(DEFAULT_LISTEN).parse()?,
+            timeout: match opt.timeout_ms {
+                Some(0) => return Err("the timeout must be positive".into()),
+                ms => ms.map(Duration::from_millis),
+            },
+            concurrency_limit: match opt.concurrency_limit {
+                Some(0) => return Err("the concurrency limit must be positive".into()),
+                limit => limit,
+            },
+            limits: LimitConfig {
+                rate_limit: rate_limit(opt.rate_limit, opt.rate_limit_burst)?,
+                peer_rate_limit: rate_limit(opt.peer_rate_limit, opt.peer_rate_limit_burst)?,
+                max_concurrent_requests: match opt.max_concurrent_requests {
+                    Some(0) => return Err("the concurrent requests limit must be positive".into()),
+                    max => max,
+                },
+            },
+            shutdown_deadline: Duration::from_millis(
+                opt.shutdown_deadline_ms
+                    .unwrap_or(DEFAULT_SHUTDOWN_DEADLINE_MS),
+            ),
+            shutdown_grace: Duration::from_millis(
+                opt.shutdown_grace_ms.unwrap_or(DEFAULT_SHUTDOWN_GRACE_MS),
+            ),
+            validation: ValidationConfig {
+                max_message_length: match opt.max_message_length {
+                    Some(0) => return Err("the maximum message length must be positive".into()),
+                    Some(max) => max,
+                    None => DEFAULT_MAX_MESSAGE_LENGTH,
+                },
+                max_say_many_count: match opt.max_say_many_count {
+                    Some(0) => return Err("the maximum SayMany count must be positive".into()),
+                    Some(max) => max,
+                    None => DEFAULT_MAX_SAY_MANY_COUNT,
+                },
+                forbidden_words: opt.forbidden_words.unwrap_or_default(),
+            },
+            compression: match &opt.compression {
+                Some(names) => compression::parse_list(names)?,
+                None => compression::ENCODINGS.to_vec(),
+            },
+            tls,
+            auth: AuthConfig {
+                static_tokens,
+                hmac_key: opt.auth_hmac_key,
+            },
+            metrics_listen: match &opt.metrics_listen {
+                Some(addr) => Some(addr.parse()?),
+                None => None,
+            },
+            log_format: match &opt.log_format {
+                Some(log_format) => log_format.parse()?,
+                None => LogFormat::default(),
+            },
+            issue_token: opt.issue_token,
+        })
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echo::v1::{SayManyRequest, SayRequest};
+
+    #[test]
+    // checks the defaults.
+    fn defaults() {
+        let config = ServerConfig::from_opt(ServerOpt::default()).unwrap();
+        assert_eq!(Listen::Tcp("[::1]:50051".parse().unwrap()), config.listen);
+        assert_eq!(None, config.timeout);
+        assert_eq!(None, config.concurrency_limit);
+        assert_eq!(LimitConfig::default(), config.limits);
+        assert_eq!(Duration::from_secs(30), config.shutdown_deadline);
+        assert_eq!(Duration::from_secs(5), config.shutdown_grace);
+        assert_eq!(1024, config.validation.max_message_length);
+        assert_eq!(1000, config.validation.max_say_many_count);
+        assert!(config.validation.forbidden_words.is_empty());
+        assert_eq!(compression::ENCODINGS.to_vec(), config.compression);
+        assert!(config.tls.is_none());
+        assert!(config.auth.static_tokens.is_empty());
+        assert_eq!(None, config.auth.hmac_key);
+        assert_eq!(None, config.metrics_listen);
+        assert_eq!(LogFormat::Pretty, config.log_format);
+    }
+
+    #[test]
+    // checks listen addresses parsing.
+    fn listen() {
+        assert_eq!(
+            Listen::Tcp("0.0.0.0:1234".parse().unwrap()),
+            "0.0.0.0:1234".parse().unwrap()
+        );
+        assert_eq!(
+            Listen::Unix("/tmp/echo.sock".into()),
+            "unix:/tmp/echo.sock".parse().unwrap()
+        );
+        assert!("localhost".parse::<Listen>().is_err());
+    }
+
+    #[test]
+    // checks the command line takes precedence over the config file.
+    fn command_line_over_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        std::fs::write(
+            &path,
+            "listen = \"127.0.0.1:4000\"\ntimeout_ms = 500\nconcurrency_limit = 8\nshutdown_deadline_ms = 100\n",
+        )
+        .unwrap();
+
+        let opt = ServerOpt {
+            config: Some(path),
+            listen: Some("unix:/tmp/echo.sock".into()),
+            ..Default::default()
+        };
+        let config = ServerConfig::from_opt(opt).unwrap();
+        assert_eq!(Listen::Unix("/tmp/echo.sock".into()), config.listen);
+        assert_eq!(Some(Duration::from_millis(500)), config.timeout);
+        assert_eq!(Some(8), config.concurrency_limit);
+        assert_eq!(Duration::from_millis(100), config.shutdown_deadline);
+    }
+
+    #[test]
+    // checks invalid settings are refused.
+    fn invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        std::fs::write(&path, "unknown = 1\n").unwrap();
+        let opt = ServerOpt {
+            config: Some(path),
+            ..Default::default()
+        };
+        assert!(ServerConfig::from_opt(opt).is_err());
+
+        let opt = ServerOpt {
+            tls_cert: Some("cert.pem".into()),
+            ..Default::default()
+        };
+        assert!(ServerConfig::from_opt(opt).is_err());
+
+        let opt = ServerOpt {
+            auth_tokens: Some(vec!["secret".into()]),
+            ..Default::default()
+        };
+        assert!(ServerConfig::from_opt(opt).is_err());
+
+        let opt = ServerOpt {
+            rate_limit: Some(0.0),
+            ..Default::default()
+        };
+        assert!(ServerConfig::from_opt(opt).is_err());
+
+        let opt = ServerOpt {
+            timeout_ms: Some(0),
+            ..Default::default()
+        };
+        assert!(ServerConfig::from_opt(opt).is_err());
+
+        let opt = ServerOpt {
+            concurrency_limit: Some(0),
+            ..Default::default()
+        };
+        assert!(ServerConfig::from_opt(opt).is_err());
+
+        let opt = ServerOpt {
+            max_concurrent_requests: Some(0),
+            ..Default::default()
+        };
+        assert!(ServerConfig::from_opt(opt).is_err());
+
+        let opt = ServerOpt {
+            log_format: Some("xml".into()),
+            ..Default::default()
+        };
+        assert!(ServerConfig::from_opt(opt).is_err());
+
+        let opt = ServerOpt {
+            metrics_listen: Some("localhost".into()),
+            ..Default::default()
+        };
+        assert!(ServerConfig::from_opt(opt).is_err());
+    }
+
+    #[test]
+    // checks auth tokens parsing.
+    fn auth_tokens() {
+        let opt = ServerOpt {
+            auth_tokens: Some(vec!["alice:secret".into(), "bob:s:e:c".into()]),
+            ..Default::default()
+        };
+        let config = ServerConfig::from_opt(opt).unwrap();
+        assert_eq!(
+            vec![
+                ("alice".to_string(), "secret".to_string()),
+                ("bob".to_string(), "s:e:c".to_string())
+            ],
+            config.auth.static_tokens
+        );
+    }
+
+    #[test]
+    // checks rate limits parsing.
+    fn limits() {
+        let opt = ServerOpt {
+            rate_limit: Some(2.5),
+            peer_rate_limit: Some(1.0),
+            peer_rate_limit_burst: Some(10),
+            max_concurrent_requests: Some(100),
+            ..Default::default()
+        };
+        let config = ServerConfig::from_opt(opt).unwrap();
+        assert_eq!(
+            LimitConfig {
+                rate_limit: Some(RateLimit {
+                    per_second: 2.5,
+                    burst: 3
+                }),
+                peer_rate_limit: Some(RateLimit {
+                    per_second: 1.0,
+                    burst: 10
+                }),
+                max_concurrent_requests: Some(100),
+            },
+            config.limits
+        );
+    }
+
+    #[test]
+    // checks the validation rules.
+    fn validation() {
+        let opt = ServerOpt {
+            max_message_length: Some(10),
+            max_say_many_count: Some(5),
+            forbidden_words: Some(vec!["Spam".into(), "".into()]),
+            ..Default::default()
+        };
+        let validator = ServerConfig::from_opt(opt).unwrap().validation.validator();
+        let say = |message: &str| SayRequest {
+            message: message.into(),
+        };
+        assert!(validator.check(&mut say("ham")).is_ok());
+        assert!(validator.check(&mut say("SPAM!")).is_err());
+        assert!(validator.check(&mut say("hamhamhamham")).is_err());
+        let say_many = |count| SayManyRequest {
+            message: "ham".into(),
+            count,
+        };
+        assert!(validator.check(&mut say_many(5)).is_ok());
+        assert!(validator.check(&mut say_many(6)).is_err());
+
+        let opt = ServerOpt {
+            max_message_length: Some(0),
+            ..Default::default()
+        };
+        assert!(ServerConfig::from_opt(opt).is_err());
+        let opt = ServerOpt {
+            max_say_many_count: Some(0),
+            ..Default::default()
+        };
+        assert!(ServerConfig::from_opt(opt).is_err());
+    }
+}
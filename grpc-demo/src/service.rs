@@ -0,0 +1,1338 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::{ReceiverStream, UnixListenerStream};
+use tokio_stream::StreamExt;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::server::Router;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tonic_web::GrpcWebLayer;
+
+use crate::auth::Authenticator;
+use crate::drain::{self, CancelledRpc, InFlight, InFlightLayer};
+use crate::echo;
+use crate::echo::echo_service_server::SERVICE_NAME as LEGACY_SERVICE_NAME;
+use crate::echo::v1::echo_service_server::{EchoService, EchoServiceServer};
+use crate::echo::v1::{SayManyRequest, SayRequest, SayResponse};
+use crate::gateway::GatewayLayer;
+use crate::legacy::Legacy;
+use crate::metrics::{self, Metrics, MetricsLayer};
+use crate::propagation::{PropagationLayer, RequestMetadata};
+use crate::server_config::{Listen, ServerConfig};
+use crate::telemetry::TraceLayer;
+use crate::validate::Validator;
+
+// number of responses buffered in a response stream.
+pub(crate) const STREAM_BUFFER: usize = 16;
+
+/// The echo service, refusing the requests its validator finds invalid.
+#[derive(Debug, Default)]
+pub struct MyEchoService {
+    validator: Validator,
+}
+
+impl MyEchoService {
+    pub fn new(validator: Validator) -> Self {
+        MyEchoService { validator }
+    }
+}
+
+pub(crate) fn hello(message: &str) -> SayResponse {
+    SayResponse {
+        message: format!("Hello {}!", message),
+    }
+}
+
+#[tonic::async_trait]
+impl EchoService for MyEchoService {
+    async fn say(&self, request: Request<SayRequest>) -> Result<Response<SayResponse>, Status> {
+        if let Some(metadata) = request.extensions().get::<RequestMetadata>() {
+            tracing::debug!(
+                request_id = %metadata.request_id,
+                tenant = ?metadata.tenant,
+                locale = ?metadata.locale,
+                "saying hello"
+            );
+        }
+        let mut request = request.into_inner();
+        self.validator.check(&mut request)?;
+        let resp = hello(&request.message);
+
+        Ok(Response::new(resp))
+    }
+
+    type SayManyStream = ReceiverStream<Result<SayResponse, Status>>;
+
+    async fn say_many(
+        &self,
+        request: Request<SayManyRequest>,
+    ) -> Result<Response<Self::SayManyStream>, Status> {
+        let mut request = request.into_inner();
+        self.validator.check(&mut request)?;
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            for idx in 0..request.count {
+                let resp = hello(&format!("{} #{}", request.message, idx));
+                // the client went away, stop sending.
+                if tx.send(Ok(resp)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn collect_say(
+        &self,
+        request: Request<Streaming<SayRequest>>,
+    ) -> Result<Response<SayResponse>, Status> {
+        let mut stream = request.into_inner();
+        let mut messages = Vec::new();
+        while let Some(req) = stream.next().await {
+            let mut req = req?;
+            self.validator.check(&mut req)?;
+            messages.push(req.message);
+        }
+        let resp = hello(&messages.join(", "));
+
+        Ok(Response::new(resp))
+    }
+
+    type ChatStream = ReceiverStream<Result<SayResponse, Status>>;
+
+    async fn chat(
+        &self,
+        request: Request<Streaming<SayRequest>>,
+    ) -> Result<Response<Self::ChatStream>, Status> {
+        let mut stream = request.into_inner();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let validator = self.validator.clone();
+        tokio::spawn(async move {
+            loop {
+                let req = tokio::select! {
+                    req = stream.next() => req,
+                    // nobody reads the answers anymore, e.g. the call was
+                    // cancelled: stop chatting without waiting for the client.
+                    _ = tx.closed() => break,
+                };
+                let req = match req {
+                    Some(req) => req,
+                    None => break,
+                };
+                let resp = req.and_then(|mut req| {
+                    validator.check(&mut req)?;
+                    Ok(hello(&req.message))
+                });
+                let is_err = resp.is_err();
+                // the client went away, or sent garbage: stop chatting.
+                if tx.send(resp).await.is_err() || is_err {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// register the echo service, under its v1 and legacy names, along with the
+/// standard health and reflection ones. Those are not authenticated, so load
+/// balancers and debugging tools can use them.
+pub async fn add_services<L: Clone>(
+    server: &mut Server<L>,
+    echo: EchoServiceServer<MyEchoService>,
+    authenticator: Authenticator,
+) -> Result<(Router<L>, HealthReporter), Box<dyn std::error::Error>> {
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_serving::<EchoServiceServer<MyEchoService>>()
+        .await;
+    health_reporter
+        .set_service_status(LEGACY_SERVICE_NAME, ServingStatus::Serving)
+        .await;
+
+    let reflection = || {
+        tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(echo::FILE_DESCRIPTOR_SET)
+            .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+    };
+    // grpcurl and most tools still only speak v1alpha.
+    let reflection_v1 = reflection().build_v1()?;
+    let reflection_v1alpha = reflection().build_v1alpha()?;
+
+    let echo = InterceptedService::new(echo, authenticator);
+    let router = server
+        .add_service(health_service)
+        .add_service(reflection_v1)
+        .add_service(reflection_v1alpha)
+        .add_service(Legacy::new(echo.clone()))
+        .add_service(echo);
+    Ok((router, health_reporter))
+}
+
+/// wait for the shutdown signal, then report every service as not serving.
+/// The server keeps serving for a grace period, so health checking clients
+/// see it and move away, before it shuts down and is told to drain.
+pub async fn shutdown(
+    signal: impl Future<Output = ()>,
+    health_reporter: HealthReporter,
+    grace: Duration,
+    draining: oneshot::Sender<()>,
+) {
+    signal.await;
+    health_reporter
+        .set_not_serving::<EchoServiceServer<MyEchoService>>()
+        .await;
+    health_reporter
+        .set_service_status(LEGACY_SERVICE_NAME, ServingStatus::NotServing)
+        .await;
+    health_reporter
+        .set_service_status("", ServingStatus::NotServing)
+        .await;
+    tokio::time::sleep(grace).await;
+    let _ = draining.send(());
+}
+
+/// serve the echo service with every configured layer, until the shutdown
+/// signal. It is also served to gRPC-Web clients, and through the JSON
+/// gateway, and its metrics on their own port if configured. In-flight
+/// requests are then drained, and the ones still running at the shutdown
+/// deadline are cancelled and returned.
+pub async fn serve(
+    config: &ServerConfig,
+    signal: impl Future<Output = ()>,
+) -> Result<Vec<CancelledRpc>, Box<dyn std::error::Error>> {
+    let in_flight = InFlight::new();
+    let metrics = Metrics::new();
+    // HTTP/1 is needed by browsers, for gRPC-Web and the JSON gateway.
+    let mut server = Server::builder()
+        .accept_http1(true)
+        .layer(GatewayLayer)
+        .layer(GrpcWebLayer::new())
+        .layer(TraceLayer)
+        .layer(PropagationLayer)
+        .layer(MetricsLayer::new(metrics.clone()))
+        .layer(InFlightLayer::new(in_flight.clone()))
+        .layer(config.limits.layer());
+    if let Some(timeout) = config.timeout {
+        server = server.timeout(timeout);
+    }
+    if let Some(limit) = config.concurrency_limit {
+        server = server.concurrency_limit_per_connection(limit);
+    }
+    if let Some(tls) = &config.tls {
+        server = server.tls_config(tls.load()?)?;
+    }
+
+    let mut echo = EchoServiceServer::new(MyEchoService::new(config.validation.validator()));
+    for encoding in config.compression.iter() {
+        echo = echo.accept_compressed(*encoding).send_compressed(*encoding);
+    }
+    let authenticator = config.auth.authenticator();
+    let (router, health_reporter) = add_services(&mut server, echo, authenticator).await?;
+    let (draining, draining_rx) = oneshot::channel();
+    let shutdown = shutdown(signal, health_reporter, config.shutdown_grace, draining);
+    let serve: Pin<Box<dyn Future<Output = Result<(), tonic::transport::Error>>>> =
+        match &config.listen {
+            Listen::Tcp(addr) => Box::pin(router.serve_with_shutdown(*addr, shutdown)),
+            Listen::Unix(path) => {
+                // a previous run may have left its socket behind.
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+                let incoming = UnixListenerStream::new(UnixListener::bind(path)?);
+                Box::pin(router.serve_with_incoming_shutdown(incoming, shutdown))
+            }
+        };
+
+    let metrics_server = match config.metrics_listen {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            Some(tokio::spawn(metrics::serve(listener, metrics)))
+        }
+        None => None,
+    };
+
+    let deadline = config.shutdown_deadline;
+    let cancelled = drain::serve_with_deadline(serve, draining_rx, deadline, &in_flight).await;
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
+    }
+    Ok(cancelled?)
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AnyOf, Authenticator, HmacTokens, StaticTokens};
+    use crate::echo::v1::echo_service_client::EchoServiceClient;
+    use crate::limit::{LimitLayer, RateLimit};
+    use crate::server_config::TlsConfig;
+    use crate::validate::{field_violations, TextRules};
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+    use tonic_web::GrpcWebClientLayer;
+
+    /// spawn a server on an ephemeral port, and return its address.
+    async fn spawn_server_with(server: Server) -> std::net::SocketAddr {
+        spawn_server_on(server, MyEchoService::default()).await
+    }
+
+    /// spawn a server of a given echo service on an ephemeral port, and
+    /// return its address.
+    async fn spawn_server_on(mut server: Server, service: MyEchoService) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            server
+                .add_service(EchoServiceServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        addr
+    }
+
+    /// spawn a server on an ephemeral port, and return a client to it.
+    async fn spawn_server() -> EchoServiceClient<Channel> {
+        let addr = spawn_server_with(Server::builder()).await;
+        EchoServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap()
+    }
+
+    /// self-signed certificates, written in a temporary directory.
+    struct TestCerts {
+        dir: tempfile::TempDir,
+        ca: String,
+        client_cert: String,
+        client_key: String,
+    }
+
+    impl TestCerts {
+        fn generate() -> Self {
+            let mut ca_params = rcgen::CertificateParams::new(Vec::new());
+            ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            ca_params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, "echo test ca");
+            let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+            let server = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            let client = rcgen::generate_simple_self_signed(vec!["client".into()]).unwrap();
+
+            let ca_pem = ca.serialize_pem().unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            let write = |name: &str, content: &str| {
+                std::fs::write(dir.path().join(name), content).unwrap();
+            };
+            write("ca.pem", &ca_pem);
+            write(
+                "server.pem",
+                &server.serialize_pem_with_signer(&ca).unwrap(),
+            );
+            write("server.key", &server.serialize_private_key_pem());
+            TestCerts {
+                ca: ca_pem,
+                client_cert: client.serialize_pem_with_signer(&ca).unwrap(),
+                client_key: client.serialize_private_key_pem(),
+                dir,
+            }
+        }
+
+        fn server_config(&self, mutual: bool) -> TlsConfig {
+            TlsConfig {
+                cert_path: self.dir.path().join("server.pem"),
+                key_path: self.dir.path().join("server.key"),
+                client_ca_path: if mutual {
+                    Some(self.dir.path().join("ca.pem"))
+                } else {
+                    None
+                },
+            }
+        }
+
+        fn client_config(&self, with_identity: bool) -> ClientTlsConfig {
+            let tls = ClientTlsConfig::new()
+                .ca_certificate(Certificate::from_pem(&self.ca))
+                .domain_name("localhost");
+            if with_identity {
+                tls.identity(Identity::from_pem(&self.client_cert, &self.client_key))
+            } else {
+                tls
+            }
+        }
+    }
+
+    /// spawn a TLS server, and try to call it with the given client settings.
+    async fn call_tls(
+        server_tls: &TlsConfig,
+        client_tls: Option<ClientTlsConfig>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let server = Server::builder()
+            .tls_config(server_tls.load().unwrap())
+            .unwrap();
+        let addr = spawn_server_with(server).await;
+        let channel = match client_tls {
+            Some(client_tls) => {
+                Channel::from_shared(format!("https://{}", addr))?
+                    .tls_config(client_tls)?
+                    .connect()
+                    .await?
+            }
+            None => {
+                Channel::from_shared(format!("http://{}", addr))?
+                    .connect()
+                    .await?
+            }
+        };
+        let resp = EchoServiceClient::new(channel)
+            .say(say_request("Tonic"))
+            .await?;
+        Ok(resp.into_inner().message)
+    }
+
+    fn say_request(message: &str) -> SayRequest {
+        SayRequest {
+            message: message.into(),
+        }
+    }
+
+    #[tokio::test]
+    // checks the unary call.
+    async fn say() {
+        let mut client = spawn_server().await;
+        let resp = client.say(say_request("Tonic")).await.unwrap();
+        assert_eq!("Hello Tonic!", resp.into_inner().message);
+    }
+
+    #[tokio::test]
+    // checks the server streaming call.
+    async fn say_many() {
+        let mut client = spawn_server().await;
+        let request = SayManyRequest {
+            message: "Tonic".into(),
+            count: 3,
+        };
+        let stream = client.say_many(request).await.unwrap().into_inner();
+        let messages = stream
+            .map(|resp| resp.unwrap().message)
+            .collect::<Vec<String>>()
+            .await;
+        assert_eq!(
+            vec!["Hello Tonic #0!", "Hello Tonic #1!", "Hello Tonic #2!"],
+            messages
+        );
+    }
+
+    #[tokio::test]
+    // checks the client streaming call.
+    async fn collect_say() {
+        let mut client = spawn_server().await;
+        let requests = vec![say_request("a"), say_request("b"), say_request("c")];
+        let resp = client
+            .collect_say(tokio_stream::iter(requests))
+            .await
+            .unwrap();
+        assert_eq!("Hello a, b, c!", resp.into_inner().message);
+
+        let resp = client
+            .collect_say(tokio_stream::iter(Vec::new()))
+            .await
+            .unwrap();
+        assert_eq!("Hello !", resp.into_inner().message);
+    }
+
+    #[tokio::test]
+    // checks the bidirectional streaming call answers as messages come.
+    async fn chat() {
+        let mut client = spawn_server().await;
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let mut stream = client
+            .chat(ReceiverStream::new(rx))
+            .await
+            .unwrap()
+            .into_inner();
+
+        for message in ["a", "b", "c"].iter() {
+            tx.send(say_request(message)).await.unwrap();
+            let resp = stream.next().await.unwrap().unwrap();
+            assert_eq!(format!("Hello {}!", message), resp.message);
+        }
+        drop(tx);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    // checks a TLS server answers TLS clients only.
+    async fn tls() {
+        let certs = TestCerts::generate();
+        let server_tls = certs.server_config(false);
+        assert_eq!(
+            "Hello Tonic!",
+            call_tls(&server_tls, Some(certs.client_config(false)))
+                .await
+                .unwrap()
+        );
+        assert!(call_tls(&server_tls, None).await.is_err());
+    }
+
+    #[tokio::test]
+    // checks a mutual TLS server only answers clients with a certificate.
+    async fn mutual_tls() {
+        let certs = TestCerts::generate();
+        let server_tls = certs.server_config(true);
+        assert_eq!(
+            "Hello Tonic!",
+            call_tls(&server_tls, Some(certs.client_config(true)))
+                .await
+                .unwrap()
+        );
+        assert!(call_tls(&server_tls, Some(certs.client_config(false)))
+            .await
+            .is_err());
+
+        // a certificate from another CA is refused.
+        let other_certs = TestCerts::generate();
+        assert!(call_tls(&server_tls, Some(other_certs.client_config(true)))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    // checks the server can be called over a unix socket.
+    async fn unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("echo.sock");
+        let incoming = UnixListenerStream::new(UnixListener::bind(&path).unwrap());
+        tokio::spawn(
+            Server::builder()
+                .add_service(EchoServiceServer::new(MyEchoService::default()))
+                .serve_with_incoming(incoming),
+        );
+
+        // the uri is ignored by the connector, but still has to be valid.
+        let channel = Endpoint::from_static("http://[::]:50051")
+            .connect_with_connector(tower::service_fn(move |_| {
+                let path = path.clone();
+                async move {
+                    let stream = tokio::net::UnixStream::connect(path).await?;
+                    Ok::<_, std::io::Error>(hyper_util::rt::TokioIo::new(stream))
+                }
+            }))
+            .await
+            .unwrap();
+        let resp = EchoServiceClient::new(channel)
+            .say(say_request("Tonic"))
+            .await
+            .unwrap();
+        assert_eq!("Hello Tonic!", resp.into_inner().message);
+    }
+
+    /// spawn a server requiring authentication, and return its address.
+    async fn spawn_auth_server(authenticator: Authenticator) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(EchoServiceServer::with_interceptor(
+                    MyEchoService::default(),
+                    authenticator,
+                ))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        addr
+    }
+
+    #[tokio::test]
+    // checks unauthenticated calls are rejected, and authenticated ones served.
+    async fn authentication() {
+        let hmac = HmacTokens::new(b"key".to_vec());
+        let signed_token = hmac.sign("bob", Duration::from_secs(3600));
+        let authenticator = Authenticator::new(AnyOf(vec![
+            Box::new(StaticTokens::new().add("secret".into(), "alice".into())),
+            Box::new(hmac),
+        ]));
+        let addr = spawn_auth_server(authenticator).await;
+        let channel = Channel::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+
+        let with_token = |token: Option<&str>| {
+            let mut request = tonic::Request::new(say_request("Tonic"));
+            if let Some(token) = token {
+                let header = format!("Bearer {}", token).parse().unwrap();
+                request.metadata_mut().insert("authorization", header);
+            }
+            request
+        };
+        let mut client = EchoServiceClient::new(channel);
+        for token in [None, Some("other"), Some("bob.0.00")].iter() {
+            let status = client.say(with_token(*token)).await.unwrap_err();
+            assert_eq!(tonic::Code::Unauthenticated, status.code());
+        }
+        for token in [Some("secret"), Some(signed_token.as_str())].iter() {
+            let resp = client.say(with_token(*token)).await.unwrap();
+            assert_eq!("Hello Tonic!", resp.into_inner().message);
+        }
+
+        // streaming calls are protected as well.
+        let status = client
+            .say_many(SayManyRequest {
+                message: "Tonic".into(),
+                count: 3,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::Unauthenticated, status.code());
+    }
+
+    /// spawn a server with every service, shutting down when told to after a
+    /// `grace` period, and draining its requests for at most `deadline`. The
+    /// server task returns the RPCs it cancelled.
+    async fn spawn_draining_server(
+        grace: Duration,
+        deadline: Duration,
+    ) -> (
+        std::net::SocketAddr,
+        oneshot::Sender<()>,
+        tokio::task::JoinHandle<Vec<drain::CancelledRpc>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let in_flight = InFlight::new();
+        let mut server = Server::builder().layer(InFlightLayer::new(in_flight.clone()));
+        let (router, health_reporter) = add_services(
+            &mut server,
+            EchoServiceServer::new(MyEchoService::default()),
+            Authenticator::disabled(),
+        )
+        .await
+        .unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let signal = async {
+            let _ = stopped.await;
+        };
+        let (draining, draining_rx) = oneshot::channel();
+        let serve = router.serve_with_incoming_shutdown(
+            TcpListenerStream::new(listener),
+            shutdown(signal, health_reporter, grace, draining),
+        );
+        let task = tokio::spawn(async move {
+            drain::serve_with_deadline(serve, draining_rx, deadline, &in_flight)
+                .await
+                .unwrap()
+        });
+        (addr, stop, task)
+    }
+
+    #[tokio::test]
+    // checks the health service reports the echo service, and flips it to not
+    // serving when shutting down.
+    async fn health() {
+        use tonic_health::pb::health_check_response::ServingStatus as Status;
+        use tonic_health::pb::health_client::HealthClient;
+        use tonic_health::pb::HealthCheckRequest;
+
+        let (addr, stop, _server) =
+            spawn_draining_server(Duration::ZERO, Duration::from_secs(1)).await;
+        let channel = Endpoint::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(channel);
+        for service in ["", "echo.v1.EchoService", "echo.EchoService"].iter() {
+            let request = HealthCheckRequest {
+                service: service.to_string(),
+            };
+            let resp = client.check(request).await.unwrap().into_inner();
+            assert_eq!(Status::Serving as i32, resp.status);
+        }
+
+        let request = HealthCheckRequest {
+            service: "echo.v1.EchoService".into(),
+        };
+        let mut watch = client.watch(request).await.unwrap().into_inner();
+        let resp = watch.next().await.unwrap().unwrap();
+        assert_eq!(Status::Serving as i32, resp.status);
+        stop.send(()).unwrap();
+        let resp = watch.next().await.unwrap().unwrap();
+        assert_eq!(Status::NotServing as i32, resp.status);
+    }
+
+    #[tokio::test]
+    // checks the server still serves during the grace period, once it reports
+    // not serving.
+    async fn shutdown_grace() {
+        use tonic_health::pb::health_check_response::ServingStatus as Status;
+        use tonic_health::pb::health_client::HealthClient;
+        use tonic_health::pb::HealthCheckRequest;
+
+        let grace = Duration::from_millis(300);
+        let (addr, stop, server) = spawn_draining_server(grace, Duration::from_secs(1)).await;
+        let channel = Endpoint::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut health = HealthClient::new(channel.clone());
+        let mut echo = EchoServiceClient::new(channel);
+        let request = HealthCheckRequest {
+            service: "echo.v1.EchoService".into(),
+        };
+        let mut watch = health.watch(request).await.unwrap().into_inner();
+        watch.next().await.unwrap().unwrap();
+
+        let stopped_at = std::time::Instant::now();
+        stop.send(()).unwrap();
+        let resp = watch.next().await.unwrap().unwrap();
+        assert_eq!(Status::NotServing as i32, resp.status);
+        let resp = echo.say(say_request("Tonic")).await.unwrap().into_inner();
+        assert_eq!("Hello Tonic!", resp.message);
+        assert!(!server.is_finished());
+
+        drop(watch);
+        server.await.unwrap();
+        assert!(stopped_at.elapsed() >= grace);
+    }
+
+    #[tokio::test]
+    // checks the reflection service lists the served services.
+    async fn reflection() {
+        use tonic_reflection::pb::v1::server_reflection_client::ServerReflectionClient;
+        use tonic_reflection::pb::v1::server_reflection_request::MessageRequest;
+        use tonic_reflection::pb::v1::server_reflection_response::MessageResponse;
+        use tonic_reflection::pb::v1::ServerReflectionRequest;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::builder();
+        let (router, _health_reporter) = add_services(
+            &mut server,
+            EchoServiceServer::new(MyEchoService::default()),
+            Authenticator::disabled(),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(router.serve_with_incoming(TcpListenerStream::new(listener)));
+
+        let channel = Endpoint::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = ServerReflectionClient::new(channel);
+        let request = ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(MessageRequest::ListServices(String::new())),
+        };
+        let mut stream = client
+            .server_reflection_info(tokio_stream::iter(vec![request]))
+            .await
+            .unwrap()
+            .into_inner();
+        let services = match stream.next().await.unwrap().unwrap().message_response {
+            Some(MessageResponse::ListServicesResponse(list)) => list
+                .service
+                .into_iter()
+                .map(|service| service.name)
+                .collect::<Vec<String>>(),
+            other => panic!("unexpected reflection response: {:?}", other),
+        };
+        assert!(services.contains(&"echo.v1.EchoService".to_string()));
+        assert!(services.contains(&"echo.EchoService".to_string()));
+        assert!(services.contains(&"grpc.health.v1.Health".to_string()));
+    }
+
+    #[tokio::test]
+    // checks the clients of the unversioned package are still answered.
+    async fn legacy_package() {
+        use crate::echo::echo_service_client::EchoServiceClient;
+        use crate::echo::{SayManyRequest, SayRequest};
+
+        let authenticator =
+            Authenticator::new(StaticTokens::new().add("secret".into(), "alice".into()));
+        let addr = spawn_web_server(authenticator).await;
+        let mut client = EchoServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        // the legacy service is authenticated too.
+        let request = SayRequest {
+            message: "Tonic".into(),
+        };
+        let status = client.say(request.clone()).await.unwrap_err();
+        assert_eq!(tonic::Code::Unauthenticated, status.code());
+
+        let mut request = Request::new(request);
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        let resp = client.say(request).await.unwrap();
+        assert_eq!("Hello Tonic!", resp.into_inner().message);
+
+        let mut request = Request::new(SayManyRequest {
+            message: "Tonic".into(),
+            count: 2,
+        });
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        let stream = client.say_many(request).await.unwrap().into_inner();
+        let messages = stream
+            .map(|resp| resp.unwrap().message)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(vec!["Hello Tonic #0!", "Hello Tonic #1!"], messages);
+    }
+
+    /// open a chat with a server, and check it answers a first message. Return
+    /// the chat, ready for more messages.
+    async fn open_chat(
+        addr: std::net::SocketAddr,
+    ) -> (mpsc::Sender<SayRequest>, Streaming<SayResponse>) {
+        let mut client = EchoServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let (requests, rx) = mpsc::channel(STREAM_BUFFER);
+        requests.send(say_request("Tonic")).await.unwrap();
+        let mut responses = client
+            .chat(ReceiverStream::new(rx))
+            .await
+            .unwrap()
+            .into_inner();
+        let resp = responses.next().await.unwrap().unwrap();
+        assert_eq!("Hello Tonic!", resp.message);
+        (requests, responses)
+    }
+
+    #[tokio::test]
+    // checks an in-flight call completes while the server shuts down.
+    async fn graceful_shutdown() {
+        let (addr, stop, server) =
+            spawn_draining_server(Duration::ZERO, Duration::from_secs(10)).await;
+        let (requests, mut responses) = open_chat(addr).await;
+
+        // the server waits for the chat to end.
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!server.is_finished());
+        requests.send(say_request("Prost")).await.unwrap();
+        let resp = responses.next().await.unwrap().unwrap();
+        assert_eq!("Hello Prost!", resp.message);
+
+        drop(requests);
+        assert!(responses.next().await.is_none());
+        let cancelled = server.await.unwrap();
+        assert!(cancelled.is_empty());
+    }
+
+    #[tokio::test]
+    // checks calls still running after the shutdown deadline are cancelled and
+    // reported.
+    async fn shutdown_deadline() {
+        let (addr, stop, server) =
+            spawn_draining_server(Duration::ZERO, Duration::from_millis(100)).await;
+        let (_requests, mut responses) = open_chat(addr).await;
+
+        stop.send(()).unwrap();
+        let cancelled = server.await.unwrap();
+        assert_eq!(
+            vec!["/echo.v1.EchoService/Chat"],
+            cancelled
+                .iter()
+                .map(|rpc| rpc.method.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(cancelled[0].running_for >= Duration::from_millis(100));
+        // the chat is cut.
+        assert!(!matches!(responses.next().await, Some(Ok(_))));
+    }
+
+    #[tokio::test]
+    // checks the server continues the trace of the caller.
+    async fn trace_context() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .layer(TraceLayer)
+                .add_service(EchoServiceServer::new(MyEchoService::default()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let mut client = EchoServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        let mut request = Request::new(say_request("Tonic"));
+        request.metadata_mut().insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+        let resp = client.say(request).await.unwrap();
+        let traceparent = resp
+            .metadata()
+            .get("traceparent")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+        assert_eq!("Hello Tonic!", resp.into_inner().message);
+
+        // without a trace, a new one is started.
+        let resp = client.say(say_request("Tonic")).await.unwrap();
+        assert!(resp.metadata().get("traceparent").is_some());
+    }
+
+    #[tokio::test]
+    // checks the request metadata is echoed in the responses, and a request
+    // id generated if missing.
+    async fn metadata_propagation() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .layer(PropagationLayer)
+                .add_service(EchoServiceServer::new(MyEchoService::default()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let mut client = EchoServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        let mut request = Request::new(say_request("Tonic"));
+        let metadata = request.metadata_mut();
+        metadata.insert("x-request-id", "req-42".parse().unwrap());
+        metadata.insert("x-tenant", "acme".parse().unwrap());
+        metadata.insert("x-locale", "fr-CA".parse().unwrap());
+        let resp = client.say(request).await.unwrap();
+        assert_eq!("req-42", resp.metadata().get("x-request-id").unwrap());
+        assert_eq!("acme", resp.metadata().get("x-tenant").unwrap());
+        assert_eq!("fr-CA", resp.metadata().get("x-locale").unwrap());
+
+        let resp = client.say(say_request("Tonic")).await.unwrap();
+        let request_id = resp.metadata().get("x-request-id").unwrap();
+        assert_eq!(32, request_id.len());
+        assert!(resp.metadata().get("x-tenant").is_none());
+
+        // errors carry the request id too.
+        let status = client.say(say_request("")).await.unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+        assert!(status.metadata().get("x-request-id").is_some());
+    }
+
+    /// spawn a server enforcing limits, and return its address.
+    async fn spawn_limited_server(limits: LimitLayer) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .layer(limits)
+                .add_service(EchoServiceServer::new(MyEchoService::default()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        addr
+    }
+
+    /// connect to a server from a given loopback address, to act as another
+    /// peer.
+    async fn connect_from(
+        addr: std::net::SocketAddr,
+        local_ip: &str,
+    ) -> EchoServiceClient<Channel> {
+        let local: std::net::SocketAddr = format!("{}:0", local_ip).parse().unwrap();
+        let channel = Endpoint::from_static("http://[::]:50051")
+            .connect_with_connector(tower::service_fn(move |_| async move {
+                let socket = tokio::net::TcpSocket::new_v4()?;
+                socket.bind(local)?;
+                let stream = socket.connect(addr).await?;
+                Ok::<_, std::io::Error>(hyper_util::rt::TokioIo::new(stream))
+            }))
+            .await
+            .unwrap();
+        EchoServiceClient::new(channel)
+    }
+
+    /// send a burst of calls at once, and return how many were accepted. The
+    /// others must have been rejected as resource exhausted.
+    async fn burst(client: &EchoServiceClient<Channel>, count: usize) -> usize {
+        let mut calls = Vec::new();
+        for _ in 0..count {
+            let mut client = client.clone();
+            calls.push(tokio::spawn(async move {
+                client.say(say_request("Tonic")).await
+            }));
+        }
+        let mut accepted = 0;
+        for call in calls {
+            match call.await.unwrap() {
+                Ok(_) => accepted += 1,
+                Err(status) => assert_eq!(tonic::Code::ResourceExhausted, status.code()),
+            }
+        }
+        accepted
+    }
+
+    // a rate so low no token is refilled during a test.
+    const NO_REFILL: f64 = 0.001;
+
+    #[tokio::test]
+    // checks each peer is limited on its own.
+    async fn peer_rate_limit() {
+        let limits = LimitLayer::new().peer_rate_limit(RateLimit {
+            per_second: NO_REFILL,
+            burst: 3,
+        });
+        let addr = spawn_limited_server(limits).await;
+        for local_ip in ["127.0.0.2", "127.0.0.3", "127.0.0.4"].iter() {
+            let client = connect_from(addr, local_ip).await;
+            assert_eq!(3, burst(&client, 5).await);
+            assert_eq!(0, burst(&client, 1).await);
+        }
+    }
+
+    #[tokio::test]
+    // checks the global limit is shared by every peer.
+    async fn global_rate_limit() {
+        let limits = LimitLayer::new().rate_limit(RateLimit {
+            per_second: NO_REFILL,
+            burst: 5,
+        });
+        let addr = spawn_limited_server(limits).await;
+        let mut bursts = Vec::new();
+        for local_ip in ["127.0.0.2", "127.0.0.3", "127.0.0.4"].iter() {
+            let client = connect_from(addr, local_ip).await;
+            bursts.push(tokio::spawn(async move { burst(&client, 4).await }));
+        }
+        let mut accepted = 0;
+        for burst in bursts {
+            accepted += burst.await.unwrap();
+        }
+        assert_eq!(5, accepted);
+    }
+
+    #[tokio::test]
+    // checks health checks are answered, even once the limits are reached.
+    async fn health_not_limited() {
+        use tonic_health::pb::health_check_response::ServingStatus as Status;
+        use tonic_health::pb::health_client::HealthClient;
+        use tonic_health::pb::HealthCheckRequest;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = LimitLayer::new().rate_limit(RateLimit {
+            per_second: NO_REFILL,
+            burst: 1,
+        });
+        let mut server = Server::builder().layer(limits);
+        let (router, _health_reporter) = add_services(
+            &mut server,
+            EchoServiceServer::new(MyEchoService::default()),
+            Authenticator::disabled(),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(router.serve_with_incoming(TcpListenerStream::new(listener)));
+
+        let client = connect_from(addr, "127.0.0.2").await;
+        assert_eq!(1, burst(&client, 3).await);
+        let channel = Endpoint::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut health = HealthClient::new(channel);
+        for _ in 0..3 {
+            let request = HealthCheckRequest {
+                service: "echo.v1.EchoService".into(),
+            };
+            let resp = health.check(request).await.unwrap().into_inner();
+            assert_eq!(Status::Serving as i32, resp.status);
+        }
+    }
+
+    #[tokio::test]
+    // checks running calls count against the concurrency limit until they
+    // end.
+    async fn max_concurrent_requests() {
+        let addr = spawn_limited_server(LimitLayer::new().max_concurrent_requests(2)).await;
+        let (first, mut first_responses) = open_chat(addr).await;
+        let _second = open_chat(addr).await;
+
+        let client = connect_from(addr, "127.0.0.2").await;
+        assert_eq!(0, burst(&client, 3).await);
+
+        drop(first);
+        assert!(first_responses.next().await.is_none());
+        assert_eq!(1, burst(&client, 1).await);
+    }
+
+    /// spawn a server answering gRPC-Web and JSON requests.
+    async fn spawn_web_server(authenticator: Authenticator) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::builder()
+            .accept_http1(true)
+            .layer(GatewayLayer)
+            .layer(GrpcWebLayer::new());
+        let (router, _health_reporter) = add_services(
+            &mut server,
+            EchoServiceServer::new(MyEchoService::default()),
+            authenticator,
+        )
+        .await
+        .unwrap();
+        tokio::spawn(router.serve_with_incoming(TcpListenerStream::new(listener)));
+        addr
+    }
+
+    /// send a JSON gateway request over HTTP/1, and return its status and
+    /// JSON response.
+    async fn http_request(
+        addr: std::net::SocketAddr,
+        method: http::Method,
+        body: &str,
+        token: Option<&str>,
+    ) -> (http::StatusCode, serde_json::Value) {
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let mut request = http::Request::builder()
+            .method(method)
+            .uri(format!("http://{}/v1/say", addr))
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        let request = request
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    // checks the JSON gateway calls the service, and maps errors to HTTP.
+    async fn json_gateway() {
+        let authenticator =
+            Authenticator::new(StaticTokens::new().add("secret".into(), "alice".into()));
+        let addr = spawn_web_server(authenticator).await;
+
+        let (status, body) = http_request(
+            addr,
+            http::Method::POST,
+            r#"{"message": "Tonic"}"#,
+            Some("secret"),
+        )
+        .await;
+        assert_eq!(http::StatusCode::OK, status);
+        assert_eq!(serde_json::json!({"message": "Hello Tonic!"}), body);
+
+        let (status, body) =
+            http_request(addr, http::Method::POST, r#"{"message": "Tonic"}"#, None).await;
+        assert_eq!(http::StatusCode::UNAUTHORIZED, status);
+        assert_eq!(tonic::Code::Unauthenticated as i64, body["code"]);
+
+        let (status, body) =
+            http_request(addr, http::Method::POST, "{not json", Some("secret")).await;
+        assert_eq!(http::StatusCode::BAD_REQUEST, status);
+        assert_eq!(tonic::Code::InvalidArgument as i64, body["code"]);
+
+        let (status, _) = http_request(addr, http::Method::GET, "", Some("secret")).await;
+        assert_eq!(http::StatusCode::METHOD_NOT_ALLOWED, status);
+    }
+
+    #[tokio::test]
+    // checks gRPC-Web clients are served over HTTP/1.
+    async fn grpc_web() {
+        let addr = spawn_web_server(Authenticator::disabled()).await;
+        let http = Client::builder(TokioExecutor::new())
+            .http2_only(false)
+            .build_http();
+        let web = tower::ServiceBuilder::new()
+            .layer(GrpcWebClientLayer::new())
+            .service(http);
+        let origin = format!("http://{}", addr).parse().unwrap();
+        let mut client = EchoServiceClient::with_origin(web, origin);
+
+        let resp = client.say(say_request("Tonic")).await.unwrap();
+        assert_eq!("Hello Tonic!", resp.into_inner().message);
+
+        let mut stream = client
+            .say_many(SayManyRequest {
+                message: "Tonic".into(),
+                count: 2,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut messages = Vec::new();
+        while let Some(resp) = stream.next().await {
+            messages.push(resp.unwrap().message);
+        }
+        assert_eq!(vec!["Hello Tonic #0!", "Hello Tonic #1!"], messages);
+    }
+
+    #[tokio::test]
+    // checks invalid requests are refused with the invalid fields.
+    async fn validation() {
+        let validator = Validator::new().forbid(|text| {
+            if text.contains("spam") {
+                Some("must not contain spam".into())
+            } else {
+                None
+            }
+        });
+        let addr = spawn_server_on(Server::builder(), MyEchoService::new(validator)).await;
+        let mut client = EchoServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        let status = client.say(say_request("")).await.unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+        assert_eq!(
+            vec!["message: must not be empty"],
+            field_violations(&status)
+        );
+        let status = client.say(say_request("spam")).await.unwrap_err();
+        assert_eq!(
+            vec!["message: must not contain spam"],
+            field_violations(&status)
+        );
+
+        // so is the number of messages asked.
+        let status = client
+            .say_many(SayManyRequest {
+                message: "ham".into(),
+                count: u32::MAX,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(
+            vec!["count: must be at most 1000"],
+            field_violations(&status)
+        );
+
+        // messages of streams are checked as well.
+        let status = client
+            .say_many(SayManyRequest {
+                message: "spam".into(),
+                count: 3,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(
+            vec!["message: must not contain spam"],
+            field_violations(&status)
+        );
+        let requests = vec![say_request("ham"), say_request("spam")];
+        let status = client
+            .collect_say(tokio_stream::iter(requests))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+        let requests = vec![say_request("ham"), say_request("")];
+        let mut responses = client
+            .chat(tokio_stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            "Hello ham!",
+            responses.next().await.unwrap().unwrap().message
+        );
+        let status = responses.next().await.unwrap().unwrap_err();
+        assert_eq!(
+            vec!["message: must not be empty"],
+            field_violations(&status)
+        );
+    }
+
+    #[tokio::test]
+    // checks large messages round-trip compressed with the negotiated
+    // compression.
+    async fn compression() {
+        use std::sync::{Arc, Mutex};
+        use tonic::codec::CompressionEncoding;
+
+        // encoding of the requests, as seen by the server.
+        let request_encodings = Arc::new(Mutex::new(Vec::new()));
+        let seen = request_encodings.clone();
+        let validator = Validator::new().message(TextRules {
+            min_chars: 1,
+            max_chars: 1 << 20,
+        });
+        let echo = EchoServiceServer::new(MyEchoService::new(validator))
+            .accept_compressed(CompressionEncoding::Gzip)
+            .send_compressed(CompressionEncoding::Gzip)
+            .accept_compressed(CompressionEncoding::Zstd)
+            .send_compressed(CompressionEncoding::Zstd);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(InterceptedService::new(echo, move |request: Request<()>| {
+                    let encoding = request
+                        .metadata()
+                        .get("grpc-encoding")
+                        .map(|encoding| encoding.to_str().unwrap().to_string());
+                    seen.lock().unwrap().push(encoding);
+                    Ok(request)
+                }))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let channel = Channel::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+
+        let message = "Tonic ".repeat(100_000);
+        for (encoding, name) in [
+            (CompressionEncoding::Gzip, "gzip"),
+            (CompressionEncoding::Zstd, "zstd"),
+        ]
+        .iter()
+        {
+            let mut client = EchoServiceClient::new(channel.clone())
+                .send_compressed(*encoding)
+                .accept_compressed(*encoding);
+            let resp = client.say(say_request(&message)).await.unwrap();
+            assert_eq!(
+                Some(*name),
+                resp.metadata()
+                    .get("grpc-encoding")
+                    .map(|encoding| encoding.to_str().unwrap())
+            );
+            assert_eq!(format!("Hello {}!", message), resp.into_inner().message);
+            assert_eq!(
+                Some(name.to_string()),
+                request_encodings.lock().unwrap().pop().unwrap()
+            );
+        }
+
+        // a client accepting no compression gets plain responses.
+        let mut client = EchoServiceClient::new(channel);
+        let resp = client.say(say_request(&message)).await.unwrap();
+        assert!(resp.metadata().get("grpc-encoding").is_none());
+        assert_eq!(None, request_encodings.lock().unwrap().pop().unwrap());
+    }
+}
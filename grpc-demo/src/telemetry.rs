@@ -0,0 +1,374 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use bytes::{Buf, Bytes};
+use http_body::{Body, Frame, SizeHint};
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo, UdsConnectInfo};
+use tonic::{Code, Status};
+use tower::{Layer, Service};
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+use tracing_subscriber::EnvFilter;
+
+/// How the logs are written.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// human readable, on several lines.
+    #[default]
+    Pretty,
+    /// one JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format {:?}, expected pretty or json",
+                format
+            )),
+        }
+    }
+}
+
+/// install the global logger. The level is taken from `RUST_LOG`, and is info
+/// by default.
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let logger = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Pretty => logger.pretty().init(),
+        LogFormat::Json => logger.json().flatten_event(true).init(),
+    }
+}
+
+/// W3C trace context of an RPC. It continues the trace of the `traceparent`
+/// metadata of the request, or starts a new one. Available in the request
+/// extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    /// 32 hex digits, shared by every span of the trace.
+    pub trace_id: String,
+    /// 16 hex digits, the span of the caller, if any.
+    pub parent_id: Option<String>,
+    /// 16 hex digits, the span of this RPC.
+    pub span_id: String,
+    pub sampled: bool,
+}
+
+/// whether a string is made of `len` lowercase hex digits, not all zeros.
+fn is_id(id: &str, len: usize) -> bool {
+    id.len() == len
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        && id.bytes().any(|b| b != b'0')
+}
+
+impl TraceContext {
+    /// continue the trace of a `traceparent` header, or start a new trace if it
+    /// is missing or invalid.
+    pub fn from_traceparent(traceparent: Option<&str>) -> Self {
+        let span_id = format!("{:016x}", rand::random::<u64>() | 1);
+        let parts = traceparent.map(|traceparent| traceparent.split('-').collect::<Vec<_>>());
+        match parts.as_deref() {
+            Some([version, trace_id, parent_id, flags])
+                if version.len() == 2
+                    && *version != "ff"
+                    && is_id(trace_id, 32)
+                    && is_id(parent_id, 16)
+                    && flags.len() == 2 =>
+            {
+                let flags = u8::from_str_radix(flags, 16).unwrap_or(0);
+                TraceContext {
+                    trace_id: trace_id.to_string(),
+                    parent_id: Some(parent_id.to_string()),
+                    span_id,
+                    sampled: flags & 1 == 1,
+                }
+            }
+            _ => TraceContext {
+                trace_id: format!("{:032x}", rand::random::<u128>() | 1),
+                parent_id: None,
+                span_id,
+                sampled: true,
+            },
+        }
+    }
+
+    /// `traceparent` header of this RPC span, for the calls it makes and its
+    /// response.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+}
+
+/// address of the client of a request, if connected with TCP.
+pub fn remote_addr<B>(request: &http::Request<B>) -> Option<SocketAddr> {
+    let extensions = request.extensions();
+    match extensions.get::<TcpConnectInfo>() {
+        Some(info) => info.remote_addr(),
+        None => extensions
+            .get::<TlsConnectInfo<TcpConnectInfo>>()
+            .and_then(|info| info.get_ref().remote_addr()),
+    }
+}
+
+/// description of the client of a request.
+fn peer<B>(request: &http::Request<B>) -> String {
+    match remote_addr(request) {
+        Some(addr) => addr.to_string(),
+        None if request.extensions().get::<UdsConnectInfo>().is_some() => "unix".into(),
+        None => "unknown".into(),
+    }
+}
+
+/// Layer running every RPC in a span holding its method, peer and trace
+/// context, and logging its status, latency and sizes once it ends.
+#[derive(Debug, Clone, Default)]
+pub struct TraceLayer;
+
+impl<S> Layer<S> for TraceLayer {
+    type Service = TraceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceService<S> {
+    inner: S,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<S, ResBody> Service<http::Request<tonic::body::Body>> for TraceService<S>
+where
+    S: Service<http::Request<tonic::body::Body>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<LoggedBody<ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<tonic::body::Body>) -> Self::Future {
+        let traceparent = request
+            .headers()
+            .get("traceparent")
+            .and_then(|traceparent| traceparent.to_str().ok());
+        let context = TraceContext::from_traceparent(traceparent);
+        let span = tracing::info_span!(
+            "rpc",
+            method = %request.uri().path(),
+            peer = %peer(&request),
+            trace_id = %context.trace_id,
+            span_id = %context.span_id,
+            parent_id = Empty,
+            request_id = Empty,
+            tenant = Empty,
+            locale = Empty,
+        );
+        if let Some(parent_id) = &context.parent_id {
+            span.record("parent_id", parent_id.as_str());
+        }
+        let traceparent = context.traceparent();
+        request.extensions_mut().insert(context);
+
+        let request_size = Arc::new(AtomicU64::new(0));
+        let request = request.map(|body| {
+            tonic::body::Body::new(CountingBody {
+                body,
+                size: request_size.clone(),
+            })
+        });
+        let mut log = RpcLog {
+            span: span.clone(),
+            started_at: Instant::now(),
+            request_size,
+            response_size: 0,
+            status: None,
+        };
+        let response = span.in_scope(|| self.inner.call(request));
+        Box::pin(
+            async move {
+                let mut response = response.await?;
+                // responses without any message carry their status in the headers.
+                if let Some(status) = Status::from_header_map(response.headers()) {
+                    log.status = Some(status.code());
+                }
+                if let Ok(traceparent) = traceparent.parse() {
+                    response.headers_mut().insert("traceparent", traceparent);
+                }
+                Ok(response.map(|body| LoggedBody { body, log }))
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// Request body counting its bytes.
+struct CountingBody {
+    body: tonic::body::Body,
+    size: Arc<AtomicU64>,
+}
+
+impl Body for CountingBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.body).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                self.size.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// What is known of an RPC, logged when its response ends.
+struct RpcLog {
+    span: Span,
+    started_at: Instant,
+    request_size: Arc<AtomicU64>,
+    response_size: u64,
+    status: Option<Code>,
+}
+
+impl Drop for RpcLog {
+    fn drop(&mut self) {
+        // a response dropped before its status means the call was cut.
+        let status = self.status.unwrap_or(Code::Cancelled);
+        tracing::info!(
+            parent: &self.span,
+            status = ?status,
+            latency_ms = self.started_at.elapsed().as_secs_f64() * 1000.0,
+            request_size = self.request_size.load(Ordering::Relaxed),
+            response_size = self.response_size,
+            "rpc finished"
+        );
+    }
+}
+
+/// Response body logging its RPC once fully sent, or dropped.
+pub struct LoggedBody<B> {
+    body: B,
+    log: RpcLog,
+}
+
+impl<B> Body for LoggedBody<B>
+where
+    B: Body + Unpin,
+    B::Data: Buf,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.body).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                self.log.response_size += data.remaining() as u64;
+            }
+            if let Some(status) = frame.trailers_ref().and_then(Status::from_header_map) {
+                self.log.status = Some(status.code());
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // checks log formats parsing.
+    fn log_format() {
+        assert_eq!(Ok(LogFormat::Pretty), "pretty".parse());
+        assert_eq!(Ok(LogFormat::Json), "json".parse());
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    // checks an incoming trace is continued.
+    fn continue_trace() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::from_traceparent(Some(traceparent));
+        assert_eq!("4bf92f3577b34da6a3ce929d0e0e4736", context.trace_id);
+        assert_eq!(Some("00f067aa0ba902b7".into()), context.parent_id);
+        assert_ne!("00f067aa0ba902b7", context.span_id);
+        assert!(context.sampled);
+        assert_eq!(
+            format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", context.span_id),
+            context.traceparent()
+        );
+
+        let unsampled = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00";
+        assert!(!TraceContext::from_traceparent(Some(unsampled)).sampled);
+    }
+
+    #[test]
+    // checks a new trace is started without a valid incoming one.
+    fn new_trace() {
+        for traceparent in [
+            None,
+            Some("garbage"),
+            Some("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            Some("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01"),
+            Some("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"),
+            Some("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        ]
+        .iter()
+        {
+            let context = TraceContext::from_traceparent(*traceparent);
+            assert_eq!(None, context.parent_id);
+            assert!(is_id(&context.trace_id, 32));
+            assert!(is_id(&context.span_id, 16));
+            assert_ne!("4bf92f3577b34da6a3ce929d0e0e4736", context.trace_id);
+        }
+    }
+}
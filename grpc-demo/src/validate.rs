@@ -0,0 +1,263 @@
+use std::fmt;
+use std::sync::Arc;
+
+use tonic::{Code, Status};
+use tonic_types::{ErrorDetails, FieldViolation, StatusExt};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::echo::v1::{SayManyRequest, SayRequest};
+
+/// Length limits of a text field, in characters once normalized.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextRules {
+    pub min_chars: usize,
+    pub max_chars: usize,
+}
+
+/// Forbidden content check: the reason a text is forbidden, if it is.
+pub type ContentCheck = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// Rules the requests must follow. Text fields are normalized to Unicode NFC
+/// before being checked, so the service only sees normalized text. By default,
+/// messages must be 1 to 1024 characters long, and at most 1000 of them can
+/// be asked at once.
+#[derive(Clone)]
+pub struct Validator {
+    message: TextRules,
+    // most messages a SayMany call may ask for.
+    max_count: u32,
+    forbidden: Option<ContentCheck>,
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Validator {
+            message: TextRules {
+                min_chars: 1,
+                max_chars: 1024,
+            },
+            max_count: 1000,
+            forbidden: None,
+        }
+    }
+}
+
+impl fmt::Debug for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Validator")
+            .field("message", &self.message)
+            .field("max_count", &self.max_count)
+            .field("forbidden", &self.forbidden.is_some())
+            .finish()
+    }
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Validator {
+            ..Default::default()
+        }
+    }
+
+    /// set the length limits of the messages.
+    pub fn message(mut self, rules: TextRules) -> Self {
+        self.message = rules;
+        self
+    }
+
+    /// set the maximum number of messages a SayMany call may ask for.
+    pub fn max_count(mut self, max: u32) -> Self {
+        self.max_count = max;
+        self
+    }
+
+    /// refuse the texts a check finds forbidden.
+    pub fn forbid(
+        mut self,
+        check: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.forbidden = Some(Arc::new(check));
+        self
+    }
+
+    /// normalize a text field, and add its violations of the rules.
+    fn text(
+        &self,
+        field: &str,
+        text: &mut String,
+        rules: &TextRules,
+        violations: &mut Vec<FieldViolation>,
+    ) {
+        // there is no point normalizing megabytes of text to refuse them anyway.
+        if text.len() <= rules.max_chars.saturating_mul(4) {
+            *text = text.nfc().collect();
+        }
+        let chars = text.chars().count();
+        if chars == 0 && rules.min_chars > 0 {
+            violations.push(FieldViolation::new(field, "must not be empty"));
+        } else if chars < rules.min_chars {
+            violations.push(FieldViolation::new(
+                field,
+                format!("must be at least {} characters long", rules.min_chars),
+            ));
+        } else if chars > rules.max_chars {
+            violations.push(FieldViolation::new(
+                field,
+                format!("must be at most {} characters long", rules.max_chars),
+            ));
+        } else if let Some(reason) = self.forbidden.as_ref().and_then(|check| check(text)) {
+            violations.push(FieldViolation::new(field, reason));
+        }
+    }
+
+    /// normalize a request, or refuse it with `INVALID_ARGUMENT`, detailing
+    /// each invalid field in a `google.rpc.BadRequest`.
+    pub fn check(&self, request: &mut impl Validate) -> Result<(), Status> {
+        let violations = request.violations(self);
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(Status::with_error_details(
+            Code::InvalidArgument,
+            "invalid request",
+            ErrorDetails::with_bad_request(violations),
+        ))
+    }
+}
+
+/// Requests checked by a `Validator`, declaring the rules of their fields.
+pub trait Validate {
+    /// normalize the fields, and return the invalid ones.
+    fn violations(&mut self, validator: &Validator) -> Vec<FieldViolation>;
+}
+
+impl Validate for SayRequest {
+    fn violations(&mut self, validator: &Validator) -> Vec<FieldViolation> {
+        let mut violations = Vec::new();
+        validator.text(
+            "message",
+            &mut self.message,
+            &validator.message,
+            &mut violations,
+        );
+        violations
+    }
+}
+
+impl Validate for SayManyRequest {
+    fn violations(&mut self, validator: &Validator) -> Vec<FieldViolation> {
+        let mut violations = Vec::new();
+        validator.text(
+            "message",
+            &mut self.message,
+            &validator.message,
+            &mut violations,
+        );
+        if self.count > validator.max_count {
+            violations.push(FieldViolation::new(
+                "count",
+                format!("must be at most {}", validator.max_count),
+            ));
+        }
+        violations
+    }
+}
+
+/// invalid fields detailed by a status, as `field: description`.
+pub fn field_violations(status: &Status) -> Vec<String> {
+    status
+        .get_details_bad_request()
+        .map(|bad_request| {
+            bad_request
+                .field_violations
+                .iter()
+                .map(|violation| format!("{}: {}", violation.field, violation.description))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn say(message: &str) -> SayRequest {
+        SayRequest {
+            message: message.into(),
+        }
+    }
+
+    #[test]
+    // checks messages length limits.
+    fn length() {
+        let validator = Validator::new().message(TextRules {
+            min_chars: 1,
+            max_chars: 5,
+        });
+        assert!(validator.check(&mut say("Tonic")).is_ok());
+        // characters are counted, not bytes.
+        assert!(validator.check(&mut say("Tönïç")).is_ok());
+
+        for message in ["", "Tonic!", &"x".repeat(1 << 20)].iter() {
+            let status = validator.check(&mut say(message)).unwrap_err();
+            assert_eq!(Code::InvalidArgument, status.code());
+            assert_eq!(1, field_violations(&status).len());
+            assert!(field_violations(&status)[0].starts_with("message: "));
+        }
+
+        // huge limits are fine.
+        let validator = Validator::new().message(TextRules {
+            min_chars: 1,
+            max_chars: usize::MAX,
+        });
+        assert!(validator.check(&mut say("Tonic")).is_ok());
+    }
+
+    #[test]
+    // checks the number of messages asked at once is limited.
+    fn count() {
+        let validator = Validator::new().max_count(10);
+        let say_many = |message: &str, count| SayManyRequest {
+            message: message.into(),
+            count,
+        };
+        assert!(validator.check(&mut say_many("Tonic", 0)).is_ok());
+        assert!(validator.check(&mut say_many("Tonic", 10)).is_ok());
+        let status = validator.check(&mut say_many("", u32::MAX)).unwrap_err();
+        assert_eq!(Code::InvalidArgument, status.code());
+        assert_eq!(
+            vec!["message: must not be empty", "count: must be at most 10"],
+            field_violations(&status)
+        );
+    }
+
+    #[test]
+    // checks messages are normalized.
+    fn normalization() {
+        let validator = Validator::new();
+        // "e" followed by a combining acute accent.
+        let mut request = say("Te\u{301}a");
+        validator.check(&mut request).unwrap();
+        assert_eq!("T\u{e9}a", request.message);
+    }
+
+    #[test]
+    // checks forbidden content is refused with its reason.
+    fn forbidden() {
+        let validator = Validator::new().forbid(|text| {
+            if text.contains("spam") {
+                Some("must not contain spam".into())
+            } else {
+                None
+            }
+        });
+        assert!(validator.check(&mut say("ham")).is_ok());
+        let status = validator.check(&mut say("spam")).unwrap_err();
+        assert_eq!(
+            vec!["message: must not contain spam"],
+            field_violations(&status)
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Approximate version of the `Ranker`, for huge populations where exact per
+/// bucket counts are not needed (e.g. "top 12%" displays). It uses a constant
+/// memory, no matter the number of users or the pops numbers range.
+///
+/// Pops numbers are kept in a KLL quantile sketch. As such sketches cannot
+/// forget a value, removed pops numbers are kept in a second sketch, and
+/// subtracted from the first one when computing a rank.
+///
+/// Error bounds:
+///   With an accuracy k, each sketch gives the number of values above any pops
+///   number with an error below 2/k of its number of values, with 99%
+///   confidence (k = 200 gives 1%). The rank error is thus bounded by
+///   2/k * (added users + removed users), transfers counting as both.
+///   While a sketch holds less than k values, it is exact: the first
+///   compaction happens when its k-th value is added.
+#[derive(Debug)]
+pub struct ApproxRanker {
+    // pops numbers of the added users.
+    added: Sketch,
+    // pops numbers of the removed users.
+    removed: Sketch,
+    // coin flips used when compacting the sketches.
+    rng: StdRng,
+}
+
+// default accuracy of the sketches.
+const DEFAULT_K: usize = 200;
+
+/// KLL sketch: a hierarchy of compactors. Values at a given level have a weight
+/// of 2^level. When a level is full, it is sorted and one value out of two is
+/// promoted to the next level, doubling its weight.
+#[derive(Debug)]
+struct Sketch {
+    k: usize,
+    levels: Vec<Vec<u32>>,
+    // number of values currently retained, across all levels.
+    size: usize,
+}
+
+impl Sketch {
+    fn new(k: usize) -> Self {
+        Sketch {
+            k,
+            levels: vec![Vec::new()],
+            size: 0,
+        }
+    }
+
+    /// capacity of a level, the top level having the biggest one.
+    fn capacity(&self, level: usize) -> usize {
+        let depth = (self.levels.len() - level - 1) as i32;
+        let capacity = (self.k as f64 * (2.0f64 / 3.0).powi(depth)).ceil() as usize;
+        std::cmp::max(2, capacity)
+    }
+
+    fn max_size(&self) -> usize {
+        (0..self.levels.len())
+            .map(|level| self.capacity(level))
+            .sum()
+    }
+
+    fn add(&mut self, value: u32, rng: &mut StdRng) {
+        self.levels[0].push(value);
+        self.size += 1;
+        if self.size >= self.max_size() {
+            self.compress(rng);
+        }
+    }
+
+    /// compact the first full level into the next one.
+    fn compress(&mut self, rng: &mut StdRng) {
+        for level in 0..self.levels.len() {
+            if self.levels[level].len() < self.capacity(level) {
+                continue;
+            }
+            if level + 1 == self.levels.len() {
+                self.levels.push(Vec::new());
+            }
+            let mut values = std::mem::take(&mut self.levels[level]);
+            values.sort_unstable();
+            // an odd value can't be paired, it stays on this level.
+            if values.len() % 2 == 1 {
+                self.levels[level].push(values.pop().unwrap());
+            }
+            let offset = rng.gen_range(0..2);
+            let promoted = values.iter().skip(offset).step_by(2);
+            self.size -= values.len() - promoted.len();
+            self.levels[level + 1].extend(promoted);
+            return;
+        }
+    }
+
+    /// estimate the number of values strictly above the given one.
+    fn count_above(&self, value: u32) -> u64 {
+        self.levels
+            .iter()
+            .enumerate()
+            .map(|(level, values)| {
+                let count = values.iter().filter(|v| **v > value).count() as u64;
+                count << level
+            })
+            .sum()
+    }
+}
+
+impl Default for ApproxRanker {
+    fn default() -> Self {
+        ApproxRanker::new()
+    }
+}
+
+impl ApproxRanker {
+    /// instanciate a ranker with the default accuracy.
+    pub fn new() -> Self {
+        ApproxRanker::with_accuracy(DEFAULT_K)
+    }
+
+    /// instanciate a ranker with a given accuracy. Memory grows linearly with
+    /// k, while the error decreases as 1/k.
+    pub fn with_accuracy(k: usize) -> Self {
+        ApproxRanker::with_rng(k, StdRng::from_entropy())
+    }
+
+    fn with_rng(k: usize, rng: StdRng) -> Self {
+        ApproxRanker {
+            added: Sketch::new(k),
+            removed: Sketch::new(k),
+            rng,
+        }
+    }
+
+    /// add a new user with a given number of pops.
+    pub fn add(&mut self, pops_number: u32) {
+        self.added.add(pops_number, &mut self.rng);
+    }
+
+    /// remove a user with a given number of pops.
+    pub fn remove(&mut self, pops_number: u32) {
+        self.removed.add(pops_number, &mut self.rng);
+    }
+
+    /// change the pops number of a given user.
+    pub fn transfer(&mut self, old_pops_number: u32, new_pops_number: u32) {
+        if old_pops_number == new_pops_number {
+            return;
+        }
+        self.remove(old_pops_number);
+        self.add(new_pops_number);
+    }
+
+    /// get the approximate rank associated to a pops number.
+    pub fn get_rank(&mut self, pops_number: u32) -> u32 {
+        let added = self.added.count_above(pops_number);
+        let removed = self.removed.count_above(pops_number);
+        added.saturating_sub(removed) as u32 + 1
+    }
+
+    pub fn get_ranks(&mut self, pops_numbers: &[u32]) -> Vec<u32> {
+        pops_numbers
+            .iter()
+            .map(|pops_number| self.get_rank(*pops_number))
+            .collect::<Vec<u32>>()
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ranker::Ranker;
+
+    const NB_USER: u32 = 1234;
+
+    fn seeded(k: usize) -> ApproxRanker {
+        ApproxRanker::with_rng(k, StdRng::seed_from_u64(42))
+    }
+
+    /// checks every rank of the approximate ranker is within `max_error` of
+    /// the exact one.
+    fn assert_close(approx: &mut ApproxRanker, exact: &mut Ranker, max_error: f64) {
+        for pops_number in 0..NB_USER + 1 {
+            let expected = exact.get_rank(pops_number) as f64;
+            let got = approx.get_rank(pops_number) as f64;
+            assert!(
+                (expected - got).abs() <= max_error,
+                "pops {}: got rank {}, expected {} (max error {})",
+                pops_number,
+                got,
+                expected,
+                max_error
+            );
+        }
+    }
+
+    #[test]
+    // checks empty state is fine.
+    fn empty_state() {
+        let mut ranker = ApproxRanker::new();
+        assert_eq!(ranker.get_rank(0), 1);
+        assert_eq!(ranker.get_rank(100000), 1);
+    }
+
+    #[test]
+    // checks populations of less than k values are ranked exactly, and the
+    // k-th value starts compacting.
+    fn exact_when_small() {
+        let mut ranker = ApproxRanker::new();
+        ranker.add(3);
+        ranker.add(5);
+        ranker.add(5);
+        ranker.add(8);
+        ranker.add(8);
+        ranker.add(8);
+        ranker.transfer(3, 9);
+
+        let expected_idx = [0, 6, 4, 1, 8, 5, 3, 9, 10000];
+        let expected_val = vec![7, 5, 7, 7, 2, 5, 7, 1, 1];
+        assert_eq!(expected_val, ranker.get_ranks(&expected_idx));
+
+        // distinct pops numbers, so that any compaction changes some rank.
+        let k = DEFAULT_K as u32;
+        let mut approx = seeded(DEFAULT_K);
+        let mut exact = Ranker::new();
+        for pops_number in 0..k - 1 {
+            approx.add(pops_number);
+            exact.add(pops_number);
+        }
+        let pops_numbers = (0..k + 1).collect::<Vec<u32>>();
+        assert_eq!(
+            exact.get_ranks(&pops_numbers),
+            approx.get_ranks(&pops_numbers)
+        );
+
+        approx.add(k - 1);
+        exact.add(k - 1);
+        assert_ne!(
+            exact.get_ranks(&pops_numbers),
+            approx.get_ranks(&pops_numbers)
+        );
+    }
+
+    #[test]
+    // checks adding many pops stays within the error bounds.
+    fn add_many_error_bound() {
+        let k = DEFAULT_K;
+        let mut approx = seeded(k);
+        let mut exact = Ranker::new();
+        let mut rng = StdRng::seed_from_u64(7);
+        let nb_ops = 100000;
+        for _ in 0..nb_ops {
+            let nb_pops = rng.gen_range(0..NB_USER);
+            approx.add(nb_pops);
+            exact.add(nb_pops);
+        }
+        assert_close(&mut approx, &mut exact, 2.0 / k as f64 * nb_ops as f64);
+    }
+
+    #[test]
+    // checks transfering pops stays within the error bounds.
+    fn transfer_many_error_bound() {
+        let k = DEFAULT_K;
+        let mut approx = seeded(k);
+        let mut exact = Ranker::new();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut users = Vec::new();
+        for _ in 0..50000 {
+            // skewed population: most users have few pops.
+            let nb_pops = rng.gen_range(0..NB_USER) * rng.gen_range(0..NB_USER) / NB_USER;
+            approx.add(nb_pops);
+            exact.add(nb_pops);
+            users.push(nb_pops);
+        }
+        for _ in 0..50000 {
+            let user = rng.gen_range(0..users.len());
+            let nb_pops = rng.gen_range(0..NB_USER);
+            approx.transfer(users[user], nb_pops);
+            exact.transfer(users[user], nb_pops);
+            users[user] = nb_pops;
+        }
+        // 50000 adds, then 50000 transfers counting as an add and a remove.
+        let nb_ops = 150000;
+        assert_close(&mut approx, &mut exact, 2.0 / k as f64 * nb_ops as f64);
+    }
+
+    #[test]
+    // checks memory usage does not depend on the population size.
+    fn bounded_size() {
+        let mut ranker = seeded(DEFAULT_K);
+        for i in 0..1000000 {
+            ranker.add(i);
+        }
+        assert!(ranker.added.size <= 4 * DEFAULT_K);
+    }
+}
@@ -0,0 +1,242 @@
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::MissedTickBehavior;
+
+use crate::decay::{Clock, Decay, SystemClock};
+use crate::user_rank::UserRank;
+
+/// Async version of the `UserRank`, to embed in tokio services. The ranking is
+/// owned by a dedicated actor task, so callers never block a worker thread on a
+/// lock: they send a command and await its answer. When pops decay, the actor
+/// also applies the decay once per decay period.
+/// This object is cheap to clone, every clone talks to the same actor. The
+/// actor stops once every clone is dropped.
+#[derive(Debug, Clone)]
+pub struct AsyncUserRank {
+    commands: mpsc::Sender<Command>,
+}
+
+#[derive(Debug)]
+enum Command {
+    UpdateUserPops {
+        user_uuid: String,
+        pops_number: u32,
+        reply: oneshot::Sender<Option<u32>>,
+    },
+    UserPops {
+        user_uuid: String,
+        reply: oneshot::Sender<Option<u32>>,
+    },
+    Histogram {
+        reply: oneshot::Sender<Vec<u32>>,
+    },
+    WorldRankings {
+        pops_numbers: Vec<u32>,
+        reply: oneshot::Sender<Vec<u32>>,
+    },
+}
+
+// number of commands waiting for the actor, before callers have to wait.
+const COMMANDS_BUFFER: usize = 1024;
+
+/// process commands until every handle is dropped, decaying pops periodically.
+/// The user rank is only accessed from this task, so its locks are never
+/// contended.
+async fn run(user_rank: UserRank, mut commands: mpsc::Receiver<Command>) {
+    let mut sweep = user_rank.decay().period().map(|period| {
+        let mut sweep = tokio::time::interval(period);
+        sweep.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        sweep
+    });
+    loop {
+        let next_sweep = async {
+            match &mut sweep {
+                Some(sweep) => sweep.tick().await,
+                None => std::future::pending().await,
+            }
+        };
+        let command = tokio::select! {
+            command = commands.recv() => command,
+            _ = next_sweep => {
+                user_rank.apply_decay();
+                continue;
+            }
+        };
+        let command = match command {
+            Some(command) => command,
+            None => return,
+        };
+        // a caller may have given up waiting, its answer is just dropped.
+        match command {
+            Command::UpdateUserPops {
+                user_uuid,
+                pops_number,
+                reply,
+            } => {
+                let _ = reply.send(user_rank.update_user_pops(user_uuid, pops_number));
+            }
+            Command::UserPops { user_uuid, reply } => {
+                let _ = reply.send(user_rank.user_pops(&user_uuid));
+            }
+            Command::Histogram { reply } => {
+                let _ = reply.send(user_rank.histogram());
+            }
+            Command::WorldRankings {
+                pops_numbers,
+                reply,
+            } => {
+                let _ = reply.send(user_rank.world_rankings(&pops_numbers));
+            }
+        }
+    }
+}
+
+impl AsyncUserRank {
+    /// spawn the actor on the current tokio runtime.
+    pub fn new() -> Self {
+        AsyncUserRank::with_decay(Decay::None, Arc::new(SystemClock))
+    }
+
+    /// spawn the actor on the current tokio runtime, pops decaying with time as
+    /// given by a clock. panics if the decay is not valid.
+    pub fn with_decay(decay: Decay, clock: Arc<dyn Clock>) -> Self {
+        let (commands, receiver) = mpsc::channel(COMMANDS_BUFFER);
+        tokio::spawn(run(UserRank::with_decay(decay, clock), receiver));
+        AsyncUserRank { commands }
+    }
+
+    /// send a command to the actor, and wait for its answer.
+    async fn call<T>(&self, command: Command, reply: oneshot::Receiver<T>) -> T {
+        self.commands
+            .send(command)
+            .await
+            .expect("user rank actor stopped");
+        reply.await.expect("user rank actor stopped")
+    }
+
+    /// updates the world ranking by update a user pops number.
+    /// returns the previous pops number of the user, if any.
+    pub async fn update_user_pops(&self, user_uuid: String, pops_number: u32) -> Option<u32> {
+        let (reply, receiver) = oneshot::channel();
+        let command = Command::UpdateUserPops {
+            user_uuid,
+            pops_number,
+            reply,
+        };
+        self.call(command, receiver).await
+    }
+
+    /// return the pops number of a user, as stored in the ranking.
+    pub async fn user_pops(&self, user_uuid: String) -> Option<u32> {
+        let (reply, receiver) = oneshot::channel();
+        self.call(Command::UserPops { user_uuid, reply }, receiver)
+            .await
+    }
+
+    /// return the number of users per pops number, indexed by pops number.
+    pub async fn histogram(&self) -> Vec<u32> {
+        let (reply, receiver) = oneshot::channel();
+        self.call(Command::Histogram { reply }, receiver).await
+    }
+
+    /// return the world rankings of the given pops.
+    pub async fn world_rankings(&self, pops_numbers: Vec<u32>) -> Vec<u32> {
+        let (reply, receiver) = oneshot::channel();
+        let command = Command::WorldRankings {
+            pops_numbers,
+            reply,
+        };
+        self.call(command, receiver).await
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    // checks empty state is fine.
+    async fn empty_state() {
+        let user_rank = AsyncUserRank::new();
+        assert_eq!(vec![1, 1], user_rank.world_rankings(vec![0, 100000]).await);
+        assert_eq!(None, user_rank.user_pops("u-1".into()).await);
+        assert!(user_rank.histogram().await.is_empty());
+    }
+
+    #[tokio::test]
+    // checks it behaves like the sync version.
+    async fn same_as_sync() {
+        let user_rank = AsyncUserRank::new();
+        let expected = UserRank::new();
+        let mut rng = rand::thread_rng();
+        for order in 0..2000 {
+            let user_uuid = format!("u-{}", order % 300);
+            let pops_number = rand::Rng::gen_range(&mut rng, 0..20000);
+            assert_eq!(
+                expected.update_user_pops(user_uuid.clone(), pops_number),
+                user_rank.update_user_pops(user_uuid, pops_number).await
+            );
+        }
+        let pops_numbers = (0..10001).collect::<Vec<u32>>();
+        assert_eq!(
+            expected.world_rankings(&pops_numbers),
+            user_rank.world_rankings(pops_numbers).await
+        );
+        assert_eq!(expected.histogram(), user_rank.histogram().await);
+        assert_eq!(
+            expected.user_pops("u-1"),
+            user_rank.user_pops("u-1".into()).await
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    // checks concurrent updates from several tasks are all applied.
+    async fn concurrent_updates() {
+        let user_rank = AsyncUserRank::new();
+        let mut tasks = Vec::new();
+        for task in 0..8 {
+            let user_rank = user_rank.clone();
+            tasks.push(tokio::spawn(async move {
+                for i in 0..100 {
+                    let user_uuid = format!("u-{}-{}", task, i);
+                    user_rank.update_user_pops(user_uuid, 10).await;
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(vec![801, 1], user_rank.world_rankings(vec![0, 10]).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    // checks pops decay every period, without any call.
+    async fn periodic_decay() {
+        use crate::decay::ManualClock;
+        use std::time::Duration;
+
+        const DAY: Duration = Duration::from_secs(24 * 3600);
+        let clock = Arc::new(ManualClock::new());
+        let decay = Decay::Linear {
+            period: DAY,
+            amount: 10,
+        };
+        let user_rank = AsyncUserRank::with_decay(decay, clock.clone());
+        user_rank.update_user_pops("u-1".into(), 40).await;
+        user_rank.update_user_pops("u-2".into(), 35).await;
+        assert_eq!(vec![2, 1], user_rank.world_rankings(vec![35, 40]).await);
+
+        // check between two sweeps.
+        tokio::time::sleep(DAY / 2).await;
+        for day in 1..4 {
+            clock.advance(DAY);
+            tokio::time::sleep(DAY).await;
+            assert_eq!(Some(40 - 10 * day), user_rank.user_pops("u-1".into()).await);
+        }
+        user_rank.update_user_pops("u-2".into(), 35).await;
+        assert_eq!(vec![1, 2], user_rank.world_rankings(vec![35, 10]).await);
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Source of time used for the pops decay. Injected, so tests can control it.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real clock.
+#[derive(Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// How the pops of an inactive user decrease over time. Decay is counted in
+/// whole periods since the last pops update of the user.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum Decay {
+    /// pops never decrease.
+    #[default]
+    None,
+    /// pops are multiplied by `factor` (between 0 and 1) every period.
+    Exponential { period: Duration, factor: f64 },
+    /// pops are decreased by `amount` every period, down to 0.
+    Linear { period: Duration, amount: u32 },
+}
+
+impl Decay {
+    /// instanciate an exponential decay, refusing factors outside 0..=1: they
+    /// would make pops grow past the ranker buckets, or silently zero them.
+    pub fn exponential(period: Duration, factor: f64) -> Result<Self, String> {
+        let decay = Decay::Exponential { period, factor };
+        if !decay.is_valid() {
+            return Err(format!("decay factor {} is not between 0 and 1", factor));
+        }
+        Ok(decay)
+    }
+
+    /// whether the decay parameters are valid.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Decay::Exponential { factor, .. } => (0.0..=1.0).contains(&factor),
+            Decay::None | Decay::Linear { .. } => true,
+        }
+    }
+
+    /// get the decay period, if pops actually decay.
+    pub fn period(&self) -> Option<Duration> {
+        match *self {
+            Decay::None => None,
+            Decay::Exponential { period, .. } | Decay::Linear { period, .. } => {
+                Some(period).filter(|period| !period.is_zero())
+            }
+        }
+    }
+
+    /// get the decayed pops number, after some time without update.
+    pub fn apply(&self, pops_number: u32, elapsed: Duration) -> u32 {
+        match *self {
+            Decay::None => pops_number,
+            Decay::Exponential { period, factor } => {
+                let periods = periods(elapsed, period);
+                (pops_number as f64 * factor.powf(periods as f64)).floor() as u32
+            }
+            Decay::Linear { period, amount } => {
+                let periods = std::cmp::min(periods(elapsed, period), u32::MAX as u128) as u32;
+                pops_number.saturating_sub(amount.saturating_mul(periods))
+            }
+        }
+    }
+}
+
+/// Parse a decay, as `none`, `exponential:<period secs>:<factor>` or
+/// `linear:<period secs>:<amount>`.
+impl FromStr for Decay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split(':').collect::<Vec<&str>>();
+        let period = |secs: &str| {
+            secs.parse::<u64>()
+                .map(Duration::from_secs)
+                .map_err(|err| format!("invalid decay period {:?}: {}", secs, err))
+        };
+        match parts.as_slice() {
+            ["none"] => Ok(Decay::None),
+            ["exponential", secs, factor] => {
+                let factor = factor
+                    .parse()
+                    .map_err(|err| format!("invalid decay factor {:?}: {}", factor, err))?;
+                Decay::exponential(period(secs)?, factor)
+            }
+            ["linear", secs, amount] => Ok(Decay::Linear {
+                period: period(secs)?,
+                amount: amount
+                    .parse()
+                    .map_err(|err| format!("invalid decay amount {:?}: {}", amount, err))?,
+            }),
+            _ => Err(format!("invalid decay {:?}", s)),
+        }
+    }
+}
+
+/// number of whole periods in an elapsed time.
+fn periods(elapsed: Duration, period: Duration) -> u128 {
+    if period.as_nanos() == 0 {
+        return 0;
+    }
+    elapsed.as_nanos() / period.as_nanos()
+}
+
+/// A clock only moving when told to.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ManualClock {
+    now: std::sync::Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            now: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    #[test]
+    // checks no decay keeps pops.
+    fn no_decay() {
+        assert_eq!(100, Decay::None.apply(100, DAY * 1000));
+    }
+
+    #[test]
+    // checks exponential decay is applied per whole period.
+    fn exponential_decay() {
+        let decay = Decay::Exponential {
+            period: DAY,
+            factor: 0.5,
+        };
+        assert_eq!(100, decay.apply(100, Duration::from_secs(0)));
+        assert_eq!(100, decay.apply(100, DAY / 2));
+        assert_eq!(50, decay.apply(100, DAY));
+        assert_eq!(25, decay.apply(100, DAY * 2 + DAY / 2));
+        assert_eq!(0, decay.apply(100, DAY * 1000));
+    }
+
+    #[test]
+    // checks linear decay is applied per whole period, down to 0.
+    fn linear_decay() {
+        let decay = Decay::Linear {
+            period: DAY,
+            amount: 10,
+        };
+        assert_eq!(100, decay.apply(100, DAY / 2));
+        assert_eq!(90, decay.apply(100, DAY));
+        assert_eq!(70, decay.apply(100, DAY * 3));
+        assert_eq!(0, decay.apply(100, DAY * 1000));
+    }
+
+    #[test]
+    // checks exponential factors outside 0..=1 are refused.
+    fn invalid_factor() {
+        assert!(Decay::exponential(DAY, 0.0).is_ok());
+        assert!(Decay::exponential(DAY, 1.0).is_ok());
+        assert!(Decay::exponential(DAY, 1.5).is_err());
+        assert!(Decay::exponential(DAY, -0.5).is_err());
+        assert!(Decay::exponential(DAY, f64::NAN).is_err());
+        assert!(!Decay::Exponential {
+            period: DAY,
+            factor: 2.0
+        }
+        .is_valid());
+    }
+
+    #[test]
+    // checks decays are parsed.
+    fn parse() {
+        assert_eq!(Ok(Decay::None), "none".parse());
+        assert_eq!(
+            Ok(Decay::Exponential {
+                period: DAY,
+                factor: 0.5
+            }),
+            "exponential:86400:0.5".parse()
+        );
+        assert_eq!(
+            Ok(Decay::Linear {
+                period: DAY,
+                amount: 10
+            }),
+            "linear:86400:10".parse()
+        );
+        assert!("exponential:86400:2".parse::<Decay>().is_err());
+        assert!("exponential:86400".parse::<Decay>().is_err());
+        assert!("linear:-1:10".parse::<Decay>().is_err());
+        assert!("weekly".parse::<Decay>().is_err());
+    }
+
+    #[test]
+    // checks an empty period never decays.
+    fn empty_period() {
+        let decay = Decay::Linear {
+            period: Duration::from_secs(0),
+            amount: 10,
+        };
+        assert_eq!(100, decay.apply(100, DAY));
+    }
+}
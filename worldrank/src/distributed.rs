@@ -0,0 +1,281 @@
+use std::net::SocketAddr;
+
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Response, Status};
+
+use crate::async_user_rank::AsyncUserRank;
+use crate::decay::{Decay, SystemClock};
+use crate::ranker::Ranker;
+use pb::world_rank_node_client::WorldRankNodeClient;
+use pb::world_rank_node_server::{WorldRankNode, WorldRankNodeServer};
+use pb::world_rank_server::{WorldRank, WorldRankServer};
+use pb::{
+    GetHistogramRequest, GetHistogramResponse, UpdateUserPopsRequest, UpdateUserPopsResponse,
+    WorldRankingsRequest, WorldRankingsResponse,
+};
+
+pub mod pb {
+    tonic::include_proto!("worldrank");
+}
+
+/// A worldrank node, owning a shard of the users. It only knows the ranks
+/// inside its shard, the coordinator merges the histograms of every node to
+/// get the world rankings.
+#[derive(Debug, Clone)]
+pub struct Node {
+    user_rank: AsyncUserRank,
+}
+
+impl Node {
+    /// instanciate an empty node, its ranking running on the current runtime
+    /// and its users pops decaying with time.
+    pub fn new(decay: Decay) -> Self {
+        Node {
+            user_rank: AsyncUserRank::with_decay(decay, std::sync::Arc::new(SystemClock)),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl WorldRankNode for Node {
+    async fn update_user_pops(
+        &self,
+        request: Request<UpdateUserPopsRequest>,
+    ) -> Result<Response<UpdateUserPopsResponse>, Status> {
+        let request = request.into_inner();
+        self.user_rank
+            .update_user_pops(request.user_uuid, request.pops_number)
+            .await;
+        Ok(Response::new(UpdateUserPopsResponse {}))
+    }
+
+    async fn get_histogram(
+        &self,
+        _request: Request<GetHistogramRequest>,
+    ) -> Result<Response<GetHistogramResponse>, Status> {
+        Ok(Response::new(GetHistogramResponse {
+            pops: self.user_rank.histogram().await,
+        }))
+    }
+}
+
+/// Dispatch the users on a fixed set of nodes, and answer the world rankings
+/// by merging the nodes histograms.
+#[derive(Debug, Clone)]
+pub struct Coordinator {
+    nodes: Vec<WorldRankNodeClient<Channel>>,
+}
+
+impl Coordinator {
+    /// connect to every nodes. A user is always dispatched to the same node, so
+    /// the list must be the same (and in the same order) between restarts, and
+    /// can't be empty.
+    pub async fn connect(
+        node_urls: Vec<String>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        if node_urls.is_empty() {
+            return Err("a coordinator needs at least one node".into());
+        }
+        let mut nodes = Vec::with_capacity(node_urls.len());
+        for node_url in node_urls {
+            nodes.push(WorldRankNodeClient::connect(node_url).await?);
+        }
+        Ok(Coordinator { nodes })
+    }
+
+    /// get the node owning a given user.
+    fn node(&self, user_uuid: &str) -> WorldRankNodeClient<Channel> {
+        self.nodes[shard(user_uuid, self.nodes.len())].clone()
+    }
+
+    /// updates the world ranking by update a user pops number on its node.
+    pub async fn update_user_pops(
+        &self,
+        user_uuid: String,
+        pops_number: u32,
+    ) -> Result<(), Status> {
+        let mut node = self.node(&user_uuid);
+        node.update_user_pops(UpdateUserPopsRequest {
+            user_uuid,
+            pops_number,
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// return the world rankings of the given pops, across every nodes.
+    pub async fn world_rankings(&self, pops_numbers: &[u32]) -> Result<Vec<u32>, Status> {
+        let histograms = futures::future::try_join_all(self.nodes.iter().map(|node| {
+            let mut node = node.clone();
+            async move { node.get_histogram(GetHistogramRequest {}).await }
+        }))
+        .await?;
+
+        let mut ranker = Ranker::new();
+        for histogram in histograms {
+            ranker.merge(&histogram.into_inner().pops);
+        }
+        Ok(ranker.get_ranks(pops_numbers))
+    }
+}
+
+/// get the index of the node owning a given user, among nb_nodes. This uses
+/// 64 bits FNV-1a, whose output is fully specified: every coordinator must
+/// agree on it, whatever the toolchain it is built with.
+fn shard(user_uuid: &str, nb_nodes: usize) -> usize {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in user_uuid.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    (hash % nb_nodes as u64) as usize
+}
+
+#[tonic::async_trait]
+impl WorldRank for Coordinator {
+    async fn update_user_pops(
+        &self,
+        request: Request<UpdateUserPopsRequest>,
+    ) -> Result<Response<UpdateUserPopsResponse>, Status> {
+        let request = request.into_inner();
+        Coordinator::update_user_pops(self, request.user_uuid, request.pops_number).await?;
+        Ok(Response::new(UpdateUserPopsResponse {}))
+    }
+
+    async fn world_rankings(
+        &self,
+        request: Request<WorldRankingsRequest>,
+    ) -> Result<Response<WorldRankingsResponse>, Status> {
+        let ranks = Coordinator::world_rankings(self, &request.into_inner().pops_numbers).await?;
+        Ok(Response::new(WorldRankingsResponse { ranks }))
+    }
+}
+
+/// serve a node, owning a shard of the users whose pops decay as given.
+pub async fn serve_node(addr: SocketAddr, decay: Decay) -> Result<(), tonic::transport::Error> {
+    Server::builder()
+        .add_service(WorldRankNodeServer::new(Node::new(decay)))
+        .serve(addr)
+        .await
+}
+
+/// serve a coordinator, dispatching the users on the given nodes.
+pub async fn serve_coordinator(
+    addr: SocketAddr,
+    node_urls: Vec<String>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let coordinator = Coordinator::connect(node_urls).await?;
+    Server::builder()
+        .add_service(WorldRankServer::new(coordinator))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_rank::UserRank;
+    use pb::world_rank_client::WorldRankClient;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    const NB_NODE: usize = 3;
+    const NB_USER: u32 = 500;
+
+    /// spawn a node on an ephemeral port, and return its url.
+    async fn spawn_node() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            Server::builder()
+                .add_service(WorldRankNodeServer::new(Node::new(Decay::None)))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        url
+    }
+
+    /// spawn a coordinator on an ephemeral port, and return its url.
+    async fn spawn_coordinator(node_urls: Vec<String>) -> String {
+        let coordinator = Coordinator::connect(node_urls).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            Server::builder()
+                .add_service(WorldRankServer::new(coordinator))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        url
+    }
+
+    #[test]
+    // checks users are always dispatched to the same nodes.
+    fn stable_shards() {
+        let shards = ["u-0", "u-1", "u-2", "u-3", "u-42", "alice"]
+            .iter()
+            .map(|user_uuid| (shard(user_uuid, 3), shard(user_uuid, 5)))
+            .collect::<Vec<(usize, usize)>>();
+        assert_eq!(vec![(0, 0), (2, 4), (1, 3), (0, 2), (0, 4), (2, 3)], shards);
+    }
+
+    #[tokio::test]
+    // checks a coordinator without nodes is refused.
+    async fn no_nodes() {
+        assert!(Coordinator::connect(Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    // checks empty state is fine.
+    async fn empty_state() {
+        let mut node_urls = Vec::new();
+        for _ in 0..NB_NODE {
+            node_urls.push(spawn_node().await);
+        }
+        let coordinator = Coordinator::connect(node_urls).await.unwrap();
+        assert_eq!(
+            vec![1, 1],
+            coordinator.world_rankings(&[0, 100000]).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    // checks the merged rankings of several nodes are the same as a single
+    // user rank.
+    async fn same_as_single_node() {
+        let mut node_urls = Vec::new();
+        for _ in 0..NB_NODE {
+            node_urls.push(spawn_node().await);
+        }
+        let mut client = WorldRankClient::connect(spawn_coordinator(node_urls).await)
+            .await
+            .unwrap();
+
+        let expected = UserRank::new();
+        let mut rng = rand::thread_rng();
+        for order in 0..2000 {
+            let user_uuid = format!("u-{}", order % NB_USER);
+            let pops_number = rand::Rng::gen_range(&mut rng, 0..1000);
+            expected.update_user_pops(user_uuid.clone(), pops_number);
+            client
+                .update_user_pops(UpdateUserPopsRequest {
+                    user_uuid,
+                    pops_number,
+                })
+                .await
+                .unwrap();
+        }
+
+        let pops_numbers = (0..1001).collect::<Vec<u32>>();
+        let ranks = client
+            .world_rankings(WorldRankingsRequest {
+                pops_numbers: pops_numbers.clone(),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .ranks;
+        assert_eq!(expected.world_rankings(&pops_numbers), ranks);
+    }
+}
@@ -1,7 +1,14 @@
+mod approx_ranker;
+mod async_user_rank;
+mod decay;
+mod distributed;
+// the ranker tests alternate between two identical iteration orders.
+#[cfg_attr(test, allow(clippy::if_same_then_else))]
 mod ranker;
+mod team_rank;
 mod user_rank;
 
-fn main() {
+fn demo() {
     let mut ranker = ranker::Ranker::new();
     println!("{}: {:?}", line!(), ranker);
     ranker.add(99);
@@ -25,6 +32,18 @@ fn main() {
 
     println!("{}: {:?}", line!(), ranker);
 
+    let mut approx_ranker = approx_ranker::ApproxRanker::new();
+    for i in 0..100000 {
+        approx_ranker.add(i % 1000);
+    }
+    approx_ranker.transfer(999, 0);
+    approx_ranker.remove(0);
+    println!(
+        "{}: {:?}",
+        line!(),
+        approx_ranker.get_ranks(&[0, 250, 500, 750, 999])
+    );
+
     let user_rank = user_rank::UserRank::new();
     user_rank.update_user_pops("u-1".into(), 10);
     user_rank.update_user_pops("u-2".into(), 20);
@@ -36,4 +55,91 @@ fn main() {
         line!(),
         user_rank.world_rankings(&[0, 10, 20, 30, 40])
     );
+
+    let decay = decay::Decay::exponential(std::time::Duration::from_secs(24 * 3600), 0.9)
+        .expect("valid decay");
+    let decaying_user_rank =
+        user_rank::UserRank::with_decay(decay, std::sync::Arc::new(decay::SystemClock));
+    decaying_user_rank.update_user_pops("u-1".into(), 10);
+    decaying_user_rank.apply_decay();
+    println!("{}: {:?}", line!(), decaying_user_rank);
+
+    let linear_decay = decay::Decay::Linear {
+        period: std::time::Duration::from_secs(24 * 3600),
+        amount: 1,
+    };
+    println!(
+        "{}: {}",
+        line!(),
+        linear_decay.apply(10, std::time::Duration::from_secs(3 * 24 * 3600))
+    );
+
+    let team_rank = team_rank::TeamRank::new();
+    team_rank.update_user_pops("u-1".into(), 10);
+    team_rank.update_user_pops("u-2".into(), 20);
+    team_rank.update_user_pops("u-3".into(), 30);
+    team_rank.join_team("u-1".into(), "t-1".into());
+    team_rank.join_team("u-2".into(), "t-1".into());
+    team_rank.join_team("u-3".into(), "t-2".into());
+    team_rank.leave_team("u-2");
+    println!("{}: {:?}", line!(), team_rank);
+    println!(
+        "{}: {:?} {:?} {:?}",
+        line!(),
+        team_rank.team_pops("t-1"),
+        team_rank.team_rank("t-1"),
+        team_rank.member_rank("u-1")
+    );
+    println!(
+        "{}: {:?}",
+        line!(),
+        team_rank.world_rankings(&[0, 10, 20, 30])
+    );
+
+    let decaying_team_rank =
+        team_rank::TeamRank::with_decay(linear_decay, std::sync::Arc::new(decay::SystemClock));
+    decaying_team_rank.update_user_pops("u-1".into(), 10);
+    decaying_team_rank.join_team("u-1".into(), "t-1".into());
+    decaying_team_rank.apply_decay();
+    println!("{}: {:?}", line!(), decaying_team_rank.team_pops("t-1"));
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = std::env::args().collect::<Vec<String>>();
+    match args.get(1).map(String::as_str) {
+        // worldrank node <listen addr> [<decay>]
+        Some("node") => {
+            let addr = args.get(2).ok_or("missing listen address")?.parse()?;
+            let decay = match args.get(3) {
+                Some(decay) => decay.parse()?,
+                None => decay::Decay::None,
+            };
+            distributed::serve_node(addr, decay).await?;
+        }
+        // worldrank coordinator <listen addr> <node url>...
+        Some("coordinator") => {
+            if args.len() < 4 {
+                return Err("usage: worldrank coordinator <listen addr> <node url>...".into());
+            }
+            let addr = args[2].parse()?;
+            distributed::serve_coordinator(addr, args[3..].to_vec())
+                .await
+                .map_err(|err| err as Box<dyn std::error::Error>)?;
+        }
+        _ => {
+            demo();
+
+            let user_rank = async_user_rank::AsyncUserRank::new();
+            user_rank.update_user_pops("u-1".into(), 10).await;
+            user_rank.update_user_pops("u-2".into(), 20).await;
+            println!("{}: {:?}", line!(), user_rank.user_pops("u-1".into()).await);
+            println!(
+                "{}: {:?}",
+                line!(),
+                user_rank.world_rankings(vec![0, 10, 20]).await
+            );
+        }
+    }
+    Ok(())
 }
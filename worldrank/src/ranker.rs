@@ -74,6 +74,15 @@ impl Ranker {
         self.dirty_index = max!(self.dirty_index, old_pops_number, new_pops_number);
     }
 
+    /// remove a user with a given number of pops.
+    pub fn remove(&mut self, pops_number: u32) {
+        if pops_number as usize >= self.pops.len() || self.pops[pops_number as usize] == 0 {
+            return;
+        }
+        self.pops[pops_number as usize] -= 1;
+        self.dirty_index = max!(self.dirty_index, pops_number);
+    }
+
     /// get the ranks associated to a pops number.
     pub fn get_rank(&mut self, pops_number: u32) -> u32 {
         // pops number is so high, you're number #1.
@@ -92,6 +101,28 @@ impl Ranker {
         self.ranks[pops_number as usize] + 1
     }
 
+    /// get the number of users per pops number, indexed by pops number.
+    /// Histograms of several rankers can be merged, as ranks are only prefix
+    /// sums of these counts.
+    pub fn histogram(&self) -> &[u32] {
+        &self.pops
+    }
+
+    /// add every users of an histogram, as returned by `histogram`.
+    pub fn merge(&mut self, histogram: &[u32]) {
+        if histogram.is_empty() {
+            return;
+        }
+        let max_pops_number = (histogram.len() - 1) as u32;
+        if histogram.len() > self.pops.len() {
+            self.resize(max_pops_number);
+        }
+        for (pops_number, count) in histogram.iter().enumerate() {
+            self.pops[pops_number] += count;
+        }
+        self.dirty_index = max!(self.dirty_index, max_pops_number);
+    }
+
     pub fn get_ranks(&mut self, pops_numbers: &[u32]) -> Vec<u32> {
         pops_numbers
             .iter()
@@ -222,4 +253,44 @@ mod tests {
         let expected_val = vec![7, 4, 6, 7, 1, 4, 6, 1];
         assert_eq!(expected_val, ranker.get_ranks(&expected_idx));
     }
+
+    #[test]
+    // test removing pops works.
+    fn remove_get() {
+        let mut ranker = Ranker::new();
+        ranker.add(3);
+        ranker.add(5);
+        ranker.add(8);
+        assert_eq!(vec![4, 3, 2, 1], ranker.get_ranks(&[0, 3, 5, 8]));
+
+        ranker.remove(5);
+        assert_eq!(vec![3, 2, 2, 1], ranker.get_ranks(&[0, 3, 5, 8]));
+
+        // removing an empty or unknown bucket is a no-op.
+        ranker.remove(5);
+        ranker.remove(100000);
+        assert_eq!(vec![3, 2, 2, 1], ranker.get_ranks(&[0, 3, 5, 8]));
+    }
+
+    #[test]
+    // test merging histograms gives the same ranks as a single ranker.
+    fn merge_histograms() {
+        let mut rng = rand::thread_rng();
+        let mut shards = [Ranker::new(), Ranker::new(), Ranker::new()];
+        let mut expected = Ranker::new();
+        for _ in 0..10000 {
+            let nb_pops = rand::Rng::gen_range(&mut rng, 0..NB_USER);
+            let shard = rand::Rng::gen_range(&mut rng, 0..shards.len());
+            shards[shard].add(nb_pops);
+            expected.add(nb_pops);
+        }
+
+        let mut merged = Ranker::new();
+        merged.merge(&[]);
+        for shard in shards.iter() {
+            merged.merge(shard.histogram());
+        }
+        let idx = (0..NB_USER + 1).collect::<Vec<u32>>();
+        assert_eq!(expected.get_ranks(&idx), merged.get_ranks(&idx));
+    }
 }
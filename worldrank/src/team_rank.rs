@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::decay::{Clock, Decay};
+use crate::ranker::Ranker;
+use crate::user_rank::UserRank;
+
+/// Handle the team (guild) ranking, on top of the users world ranking. A team
+/// pops number is the sum of its members pops numbers, decayed ones included.
+/// This object is thread safe.
+#[derive(Default, Debug)]
+pub struct TeamRank {
+    users: UserRank,
+    teams: Mutex<Teams>,
+}
+
+#[derive(Default, Debug)]
+struct Teams {
+    // team of every user belonging to one.
+    user_team: HashMap<String, String>,
+    // teams state, indexed by team uuid.
+    teams: HashMap<String, Team>,
+    // ranking between teams, using their pops sum.
+    ranker: Ranker,
+}
+
+#[derive(Default, Debug)]
+struct Team {
+    // sum of the members pops, wide enough to never overflow.
+    pops: u64,
+    // number of members.
+    size: usize,
+    // ranking of the members inside the team.
+    members: Ranker,
+}
+
+// if a team got more pops than this. it will be put in that bucket
+const MAX_TEAM_POPS: u32 = 999_999;
+
+fn team_bucket(pops_number: u64) -> u32 {
+    std::cmp::min(pops_number, MAX_TEAM_POPS as u64) as u32
+}
+
+impl Teams {
+    /// register a user with a given pops number in a team.
+    fn join(&mut self, user_uuid: String, team_uuid: String, pops_number: u32) {
+        let team = self.teams.entry(team_uuid.clone()).or_default();
+        let old_team_pops = team.pops;
+        team.pops += pops_number as u64;
+        team.size += 1;
+        team.members.add(pops_number);
+        if team.size == 1 {
+            self.ranker.add(team_bucket(team.pops));
+        } else {
+            self.ranker
+                .transfer(team_bucket(old_team_pops), team_bucket(team.pops));
+        }
+        self.user_team.insert(user_uuid, team_uuid);
+    }
+
+    /// unregister a user with a given pops number from its team, if any.
+    fn leave(&mut self, user_uuid: &str, pops_number: u32) {
+        let team_uuid = match self.user_team.remove(user_uuid) {
+            Some(team_uuid) => team_uuid,
+            None => return,
+        };
+        let team = self.teams.get_mut(&team_uuid).unwrap();
+        let old_team_pops = team.pops;
+        team.pops -= pops_number as u64;
+        team.size -= 1;
+        team.members.remove(pops_number);
+        if team.size == 0 {
+            self.ranker.remove(team_bucket(old_team_pops));
+            self.teams.remove(&team_uuid);
+        } else {
+            self.ranker
+                .transfer(team_bucket(old_team_pops), team_bucket(team.pops));
+        }
+    }
+
+    /// change the pops number of a user inside its team, if any.
+    fn transfer(&mut self, user_uuid: &str, old_pops_number: u32, new_pops_number: u32) {
+        let team_uuid = match self.user_team.get(user_uuid) {
+            Some(team_uuid) => team_uuid,
+            None => return,
+        };
+        let team = self.teams.get_mut(team_uuid).unwrap();
+        let old_team_pops = team.pops;
+        team.pops = team.pops - old_pops_number as u64 + new_pops_number as u64;
+        team.members.transfer(old_pops_number, new_pops_number);
+        self.ranker
+            .transfer(team_bucket(old_team_pops), team_bucket(team.pops));
+    }
+}
+
+impl TeamRank {
+    pub fn new() -> Self {
+        TeamRank {
+            ..Default::default()
+        }
+    }
+
+    /// instanciate a ranking where users pops decay with time, as given by a
+    /// clock. panics if the decay is not valid.
+    pub fn with_decay(decay: Decay, clock: Arc<dyn Clock>) -> Self {
+        TeamRank {
+            users: UserRank::with_decay(decay, clock),
+            teams: Default::default(),
+        }
+    }
+
+    /// decay the users pops, as `UserRank::apply_decay`, and update their
+    /// teams accordingly.
+    pub fn apply_decay(&self) {
+        let mut teams_guard = self.teams.lock().unwrap();
+        self.users
+            .decay_users(|user_uuid, old_pops_number, new_pops_number| {
+                teams_guard.transfer(user_uuid, old_pops_number, new_pops_number)
+            });
+    }
+
+    /// updates the world and team rankings by update a user pops number.
+    pub fn update_user_pops(&self, user_uuid: String, pops_number: u32) {
+        let mut teams_guard = self.teams.lock().unwrap();
+        let old_pops_number = self
+            .users
+            .update_user_pops(user_uuid.clone(), pops_number)
+            .unwrap_or(0);
+        let new_pops_number = self.users.user_pops(&user_uuid).unwrap_or(0);
+        teams_guard.transfer(&user_uuid, old_pops_number, new_pops_number);
+    }
+
+    /// put a user in a team, leaving its previous one if any. A user without
+    /// pops yet counts as having none.
+    pub fn join_team(&self, user_uuid: String, team_uuid: String) {
+        let mut teams_guard = self.teams.lock().unwrap();
+        let pops_number = self.users.user_pops(&user_uuid).unwrap_or(0);
+        teams_guard.leave(&user_uuid, pops_number);
+        teams_guard.join(user_uuid, team_uuid, pops_number);
+    }
+
+    /// remove a user from its team, if any.
+    pub fn leave_team(&self, user_uuid: &str) {
+        let mut teams_guard = self.teams.lock().unwrap();
+        let pops_number = self.users.user_pops(user_uuid).unwrap_or(0);
+        teams_guard.leave(user_uuid, pops_number);
+    }
+
+    /// return the pops number of a team, being the sum of its members pops.
+    pub fn team_pops(&self, team_uuid: &str) -> Option<u64> {
+        let teams_guard = self.teams.lock().unwrap();
+        teams_guard.teams.get(team_uuid).map(|team| team.pops)
+    }
+
+    /// return the rank of a team among all teams.
+    pub fn team_rank(&self, team_uuid: &str) -> Option<u32> {
+        let mut teams_guard = self.teams.lock().unwrap();
+        let pops_number = teams_guard.teams.get(team_uuid)?.pops;
+        Some(teams_guard.ranker.get_rank(team_bucket(pops_number)))
+    }
+
+    /// return the rank of a user among the members of its team.
+    pub fn member_rank(&self, user_uuid: &str) -> Option<u32> {
+        let mut teams_guard = self.teams.lock().unwrap();
+        let pops_number = self.users.user_pops(user_uuid).unwrap_or(0);
+        let team_uuid = teams_guard.user_team.get(user_uuid)?.clone();
+        let team = teams_guard.teams.get_mut(&team_uuid).unwrap();
+        Some(team.members.get_rank(pops_number))
+    }
+
+    /// return the world rankings of the given pops.
+    pub fn world_rankings(&self, pops_numbers: &[u32]) -> Vec<u32> {
+        self.users.world_rankings(pops_numbers)
+    }
+}
+
+////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // checks empty state is fine.
+    fn empty_state() {
+        let team_rank = TeamRank::new();
+        assert_eq!(None, team_rank.team_rank("t-1"));
+        assert_eq!(None, team_rank.member_rank("u-1"));
+        team_rank.leave_team("u-1");
+        assert_eq!(vec![1], team_rank.world_rankings(&[0]));
+    }
+
+    #[test]
+    // checks pops updates are propagated to the team rankings.
+    fn update_pops() {
+        let team_rank = TeamRank::new();
+        team_rank.join_team("u-1".into(), "t-1".into());
+        team_rank.join_team("u-2".into(), "t-1".into());
+        team_rank.join_team("u-3".into(), "t-2".into());
+        team_rank.update_user_pops("u-1".into(), 10);
+        team_rank.update_user_pops("u-2".into(), 20);
+        team_rank.update_user_pops("u-3".into(), 25);
+        assert_eq!(Some(30), team_rank.team_pops("t-1"));
+        assert_eq!(Some(25), team_rank.team_pops("t-2"));
+        assert_eq!(Some(1), team_rank.team_rank("t-1"));
+        assert_eq!(Some(2), team_rank.team_rank("t-2"));
+        assert_eq!(Some(2), team_rank.member_rank("u-1"));
+        assert_eq!(Some(1), team_rank.member_rank("u-2"));
+        assert_eq!(Some(1), team_rank.member_rank("u-3"));
+
+        team_rank.update_user_pops("u-3".into(), 40);
+        assert_eq!(Some(2), team_rank.team_rank("t-1"));
+        assert_eq!(Some(1), team_rank.team_rank("t-2"));
+        assert_eq!(vec![3, 2, 1], team_rank.world_rankings(&[10, 20, 40]));
+    }
+
+    #[test]
+    // checks membership changes are propagated to the team rankings.
+    fn change_membership() {
+        let team_rank = TeamRank::new();
+        team_rank.update_user_pops("u-1".into(), 10);
+        team_rank.update_user_pops("u-2".into(), 20);
+        team_rank.update_user_pops("u-3".into(), 25);
+        team_rank.join_team("u-1".into(), "t-1".into());
+        team_rank.join_team("u-2".into(), "t-1".into());
+        team_rank.join_team("u-3".into(), "t-2".into());
+        assert_eq!(Some(1), team_rank.team_rank("t-1"));
+
+        // moving a member to another team updates both.
+        team_rank.join_team("u-2".into(), "t-2".into());
+        assert_eq!(Some(10), team_rank.team_pops("t-1"));
+        assert_eq!(Some(45), team_rank.team_pops("t-2"));
+        assert_eq!(Some(2), team_rank.team_rank("t-1"));
+        assert_eq!(Some(1), team_rank.team_rank("t-2"));
+        assert_eq!(Some(2), team_rank.member_rank("u-2"));
+        assert_eq!(Some(1), team_rank.member_rank("u-1"));
+
+        // an emptied team is not ranked anymore.
+        team_rank.leave_team("u-1");
+        assert_eq!(None, team_rank.team_rank("t-1"));
+        assert_eq!(None, team_rank.member_rank("u-1"));
+        assert_eq!(Some(1), team_rank.team_rank("t-2"));
+    }
+
+    #[test]
+    // checks decayed users pops are propagated to their teams.
+    fn decay() {
+        use crate::decay::ManualClock;
+        use std::time::Duration;
+
+        const DAY: Duration = Duration::from_secs(24 * 3600);
+        let clock = Arc::new(ManualClock::new());
+        let decay = Decay::Linear {
+            period: DAY,
+            amount: 10,
+        };
+        let team_rank = TeamRank::with_decay(decay, clock.clone());
+        team_rank.update_user_pops("u-1".into(), 40);
+        team_rank.update_user_pops("u-2".into(), 20);
+        team_rank.update_user_pops("u-3".into(), 50);
+        team_rank.join_team("u-1".into(), "t-1".into());
+        team_rank.join_team("u-2".into(), "t-1".into());
+        team_rank.join_team("u-3".into(), "t-2".into());
+        assert_eq!(Some(1), team_rank.team_rank("t-1"));
+
+        // u-3 stays active, t-1 falls behind t-2.
+        clock.advance(DAY * 2);
+        team_rank.update_user_pops("u-3".into(), 50);
+        team_rank.apply_decay();
+        assert_eq!(Some(20), team_rank.team_pops("t-1"));
+        assert_eq!(Some(50), team_rank.team_pops("t-2"));
+        assert_eq!(Some(2), team_rank.team_rank("t-1"));
+        assert_eq!(Some(1), team_rank.member_rank("u-1"));
+
+        // leaving a team removes the decayed pops, not the updated ones.
+        team_rank.leave_team("u-1");
+        assert_eq!(Some(0), team_rank.team_pops("t-1"));
+    }
+
+    #[test]
+    // checks huge teams pops sums stay exact when members leave.
+    fn huge_team() {
+        // enough members at the maximum pops number to overflow a u32 sum.
+        let nb_members = u32::MAX / 9999 + 2;
+        let team_rank = TeamRank::new();
+        for i in 0..nb_members {
+            team_rank.update_user_pops(format!("u-{}", i), 9999);
+            team_rank.join_team(format!("u-{}", i), "t-1".into());
+        }
+        assert_eq!(Some(nb_members as u64 * 9999), team_rank.team_pops("t-1"));
+
+        for i in 1..nb_members {
+            team_rank.leave_team(&format!("u-{}", i));
+        }
+        team_rank.update_user_pops("u-0".into(), 5);
+        team_rank.update_user_pops("u-x".into(), 10);
+        team_rank.join_team("u-x".into(), "t-2".into());
+        assert_eq!(Some(5), team_rank.team_pops("t-1"));
+        assert_eq!(Some(2), team_rank.team_rank("t-1"));
+    }
+}
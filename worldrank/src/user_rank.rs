@@ -1,20 +1,41 @@
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::decay::{Clock, Decay, SystemClock};
 
 /// Handle the pops world ranking. Updates users pops, and get the ranking given
-/// a list of pops numbers.
+/// a list of pops numbers. Pops of inactive users can decay over time.
 /// This object is thread safe.
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct UserRank {
     // mutex
     // Mutex<HashMap<
-    user_pops: Mutex<HashMap<String, u32>>,
+    user_pops: Mutex<HashMap<String, UserPops>>,
     ranker: Mutex<crate::ranker::Ranker>,
+    decay: Decay,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct UserPops {
+    // pops number given at the last update.
+    updated_pops: u32,
+    // time of the last update.
+    updated_at: Instant,
+    // current pops number, once decayed. This is the one put in the ranker.
+    pops: u32,
 }
 
 // if someone got more descendant than this. it will be put in that bucket
 const MAX_POPS: u32 = 9999;
 
+impl Default for UserRank {
+    fn default() -> Self {
+        UserRank::with_decay(Decay::None, Arc::new(SystemClock))
+    }
+}
+
 impl UserRank {
     pub fn new() -> Self {
         UserRank {
@@ -22,16 +43,80 @@ impl UserRank {
         }
     }
 
-    /// updates the world ranking by update a user pops number.
-    pub fn update_user_pops(&self, user_uuid: String, pops_number: u32) {
+    /// instanciate a ranking where pops decay with time, as given by a clock.
+    /// panics if the decay is not valid.
+    pub fn with_decay(decay: Decay, clock: Arc<dyn Clock>) -> Self {
+        assert!(decay.is_valid(), "invalid decay {:?}", decay);
+        UserRank {
+            user_pops: Default::default(),
+            ranker: Default::default(),
+            decay,
+            clock,
+        }
+    }
+
+    /// updates the world ranking by update a user pops number. This also
+    /// resets the decay of the user.
+    /// returns the previous pops number of the user, if any.
+    pub fn update_user_pops(&self, user_uuid: String, pops_number: u32) -> Option<u32> {
         let new_pops_number = std::cmp::min(pops_number, MAX_POPS);
         let mut user_guard = self.user_pops.lock().unwrap();
         let mut ranker_guard = self.ranker.lock().unwrap();
         match user_guard.get(&user_uuid) {
-            Some(old_pops_number) => ranker_guard.transfer(*old_pops_number, new_pops_number),
+            Some(old) => ranker_guard.transfer(old.pops, new_pops_number),
             None => ranker_guard.add(new_pops_number),
         }
-        user_guard.insert(user_uuid, new_pops_number);
+        let new = UserPops {
+            updated_pops: new_pops_number,
+            updated_at: self.clock.now(),
+            pops: new_pops_number,
+        };
+        user_guard.insert(user_uuid, new).map(|old| old.pops)
+    }
+
+    /// return the pops number of a user, as stored in the ranking.
+    pub fn user_pops(&self, user_uuid: &str) -> Option<u32> {
+        let user_guard = self.user_pops.lock().unwrap();
+        user_guard.get(user_uuid).map(|user| user.pops)
+    }
+
+    /// return how pops decay in this ranking.
+    pub fn decay(&self) -> Decay {
+        self.decay
+    }
+
+    /// decay the pops of every users, according to the time since their last
+    /// update, and move them in the ranking accordingly. Ranks only reflect the
+    /// decay as of the last call, so this should be called periodically (e.g.
+    /// once per decay period).
+    pub fn apply_decay(&self) {
+        self.decay_users(|_, _, _| {});
+    }
+
+    /// apply the decay, calling on_change with the old and new pops numbers of
+    /// every user whose pops changed.
+    pub(crate) fn decay_users(&self, mut on_change: impl FnMut(&str, u32, u32)) {
+        if self.decay == Decay::None {
+            return;
+        }
+        let now = self.clock.now();
+        let mut user_guard = self.user_pops.lock().unwrap();
+        let mut ranker_guard = self.ranker.lock().unwrap();
+        for (user_uuid, user) in user_guard.iter_mut() {
+            let elapsed = now.saturating_duration_since(user.updated_at);
+            let decayed_pops = self.decay.apply(user.updated_pops, elapsed);
+            if decayed_pops != user.pops {
+                ranker_guard.transfer(user.pops, decayed_pops);
+                on_change(user_uuid, user.pops, decayed_pops);
+                user.pops = decayed_pops;
+            }
+        }
+    }
+
+    /// return the number of users per pops number, indexed by pops number.
+    pub fn histogram(&self) -> Vec<u32> {
+        let ranker_guard = self.ranker.lock().unwrap();
+        ranker_guard.histogram().to_vec()
     }
 
     /// return the world rankings of the given pops.
@@ -46,6 +131,10 @@ impl UserRank {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::decay::ManualClock;
+    use std::time::Duration;
+
+    const DAY: Duration = Duration::from_secs(24 * 3600);
 
     #[test]
     // checks empty state is fine.
@@ -60,4 +149,73 @@ mod tests {
             user_rank.world_rankings(&[0, 10, 20, 30, 40])
         );
     }
+
+    #[test]
+    // checks inactive users fall down the rankings.
+    fn decay_inactive_users() {
+        let clock = Arc::new(ManualClock::new());
+        let decay = Decay::Linear {
+            period: DAY,
+            amount: 10,
+        };
+        let user_rank = UserRank::with_decay(decay, clock.clone());
+        user_rank.update_user_pops("u-1".into(), 40);
+        user_rank.update_user_pops("u-2".into(), 35);
+        user_rank.update_user_pops("u-3".into(), 20);
+        assert_eq!(vec![2, 1], user_rank.world_rankings(&[35, 40]));
+
+        // nothing decays before a whole period.
+        clock.advance(DAY / 2);
+        user_rank.apply_decay();
+        assert_eq!(Some(40), user_rank.user_pops("u-1"));
+
+        // u-2 stays active, u-1 and u-3 decay.
+        user_rank.update_user_pops("u-2".into(), 35);
+        clock.advance(DAY / 2);
+        user_rank.apply_decay();
+        assert_eq!(Some(30), user_rank.user_pops("u-1"));
+        assert_eq!(Some(35), user_rank.user_pops("u-2"));
+        assert_eq!(Some(10), user_rank.user_pops("u-3"));
+        assert_eq!(vec![1, 2, 3], user_rank.world_rankings(&[35, 30, 10]));
+
+        // everyone ends up with no pops.
+        clock.advance(DAY * 10);
+        user_rank.apply_decay();
+        assert_eq!(Some(0), user_rank.user_pops("u-1"));
+        assert_eq!(Some(0), user_rank.user_pops("u-2"));
+        assert_eq!(3, user_rank.histogram()[0]);
+        assert_eq!(vec![1], user_rank.world_rankings(&[0]));
+    }
+
+    #[test]
+    // checks the ranker buckets stay consistent with the decayed pops.
+    fn decay_keeps_buckets_consistent() {
+        let clock = Arc::new(ManualClock::new());
+        let decay = Decay::Exponential {
+            period: DAY,
+            factor: 0.9,
+        };
+        let user_rank = UserRank::with_decay(decay, clock.clone());
+        let mut rng = rand::thread_rng();
+        for day in 0..30 {
+            for _ in 0..100 {
+                let user_uuid = format!("u-{}", rand::Rng::gen_range(&mut rng, 0..300));
+                let pops_number = rand::Rng::gen_range(&mut rng, 0..1000);
+                user_rank.update_user_pops(user_uuid, pops_number);
+            }
+            clock.advance(DAY / 3 * (day % 4));
+            user_rank.apply_decay();
+        }
+
+        // a fresh ranking with the decayed pops must give the same ranks.
+        let expected = UserRank::new();
+        for (user_uuid, user) in user_rank.user_pops.lock().unwrap().iter() {
+            expected.update_user_pops(user_uuid.clone(), user.pops);
+        }
+        let pops_numbers = (0..1001).collect::<Vec<u32>>();
+        assert_eq!(
+            expected.world_rankings(&pops_numbers),
+            user_rank.world_rankings(&pops_numbers)
+        );
+    }
 }